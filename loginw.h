@@ -0,0 +1,133 @@
+#pragma once
+/** libloginw client API */
+
+
+/* This is autogenerated by cbindgen. Don't modify this manually. */
+
+#include <stdint.h>
+#include <stdlib.h>
+#include <stdbool.h>
+#include "loginwproto.h"
+
+/*
+ * Opaque connection handle
+ */
+typedef struct LoginwClient LoginwClient;
+
+/*
+ * Called from loginw_dispatch, after the session was (de)activated by a VT switch.
+ * Revoked devices are paused before deactivated is called, and resumed before activated is called.
+ * A resumed device keeps its fd number (the new fd is dup'd over it, close-on-exec).
+ */
+typedef struct {
+  void (*activated)(LoginwClient *client, void *userdata);
+  void (*deactivated)(LoginwClient *client, void *userdata);
+  void (*device_paused)(LoginwClient *client, int fd, void *userdata);
+  void (*device_resumed)(LoginwClient *client, int fd, void *userdata);
+} LoginwCallbacks;
+
+/* This is autogenerated by cbindgen. Don't modify this manually. */
+
+/*
+ * Sets up the VT, stores its number in vt_num (may be NULL), returns the tty fd or -1.
+ * Each call returns a new tty fd, owned by the caller, close it when done.
+ *
+ * # Safety
+ *
+ * client must be NULL or a handle from loginw_connect_from_env that wasn't disconnected, vt_num NULL or writable.
+ */
+int loginw_acquire_vt(LoginwClient *client, uint32_t *vt_num);
+
+/*
+ * Sets up a specific VT (0 for loginw's default), stores the acquired VT number in out_vt_num (may be NULL),
+ * returns the tty fd or -1. Each call returns a new tty fd, owned by the caller, close it when done.
+ *
+ * # Safety
+ *
+ * client must be NULL or a handle from loginw_connect_from_env that wasn't disconnected, out_vt_num NULL or writable.
+ */
+int loginw_acquire_vt_number(LoginwClient *client, uint32_t vt_num, uint32_t *out_vt_num);
+
+/*
+ * Closes a device fd returned by loginw_open_device(_class), and tells loginw it's no longer used
+ *
+ * # Safety
+ *
+ * client must be NULL or a handle from loginw_connect_from_env that wasn't disconnected.
+ */
+int loginw_close_device(LoginwClient *client, int fd);
+
+/*
+ * Connects using LOGINW_FD. callbacks may be NULL. Returns NULL and sets errno on failure.
+ *
+ * # Safety
+ *
+ * callbacks must be NULL or point to a LoginwCallbacks, which is copied.
+ */
+LoginwClient *loginw_connect_from_env(const LoginwCallbacks *callbacks, void *userdata);
+
+/*
+ * Closes the connection and frees the handle
+ *
+ * # Safety
+ *
+ * client must be NULL or a handle from loginw_connect_from_env that wasn't disconnected, it can't be used afterwards.
+ */
+void loginw_disconnect(LoginwClient *client);
+
+/*
+ * Reads pending messages and runs the callbacks, returns the number of callbacks run or -1
+ *
+ * # Safety
+ *
+ * client must be NULL or a handle from loginw_connect_from_env that wasn't disconnected. The callbacks must not disconnect it.
+ */
+int loginw_dispatch(LoginwClient *client);
+
+/*
+ * The connection fd, poll it for readability and call loginw_dispatch
+ *
+ * # Safety
+ *
+ * client must be NULL or a handle from loginw_connect_from_env that wasn't disconnected.
+ */
+int loginw_get_fd(const LoginwClient *client);
+
+/*
+ * Error category of the last failed call
+ *
+ * # Safety
+ *
+ * client must be NULL or a handle from loginw_connect_from_env that wasn't disconnected.
+ */
+LoginwErrorCode loginw_last_error(const LoginwClient *client);
+
+/*
+ * Opens an input or DRM device (by full path), returns the fd or -1 and sets errno
+ *
+ * # Safety
+ *
+ * client must be NULL or a handle from loginw_connect_from_env that wasn't disconnected, path NULL or a NUL-terminated string.
+ */
+int loginw_open_device(LoginwClient *client, const char *path);
+
+/*
+ * Opens a device of the given LoginwDeviceClass (by full path), returns the fd or -1 and sets errno.
+ * Revoked devices are paused and resumed like input devices.
+ *
+ * # Safety
+ *
+ * client must be NULL or a handle from loginw_connect_from_env that wasn't disconnected, path NULL or a NUL-terminated string.
+ */
+int loginw_open_device_class(LoginwClient *client, uint16_t class, const char *path);
+
+/*
+ * Switches to another VT, returns 0 or -1
+ *
+ * # Safety
+ *
+ * client must be NULL or a handle from loginw_connect_from_env that wasn't disconnected.
+ */
+int loginw_switch_vt(LoginwClient *client, uint32_t vt_num);
+
+/* This is autogenerated by cbindgen. Don't modify this manually. */
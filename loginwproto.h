@@ -8,17 +8,89 @@
 #include <stdlib.h>
 #include <stdbool.h>
 
+/*
+ * Bumped on every change to the structs or enums below.
+ * Version 0 is the original layout, where LoginwResponse had no error info.
+ */
+#define LOGINW_PROTOCOL_VERSION 6
+
+#define LOGINW_CAP_OPEN_INPUT (1 << 0)
+
+#define LOGINW_CAP_OPEN_DRM (1 << 1)
+
+#define LOGINW_CAP_ACQUIRE_VT (1 << 2)
+
+#define LOGINW_CAP_SWITCH_VT (1 << 3)
+
+#define LOGINW_CAP_POWER_OFF (1 << 4)
+
+#define LOGINW_CAP_REBOOT (1 << 5)
+
+#define LOGINW_CAP_SUSPEND (1 << 6)
+
+#define LOGINW_CAP_HIBERNATE (1 << 7)
+
+#define LOGINW_CAP_CAN_SUSPEND (1 << 8)
+
+#define LOGINW_CAP_CAN_HIBERNATE (1 << 9)
+
+#define LOGINW_CAP_HELLO (1 << 10)
+
+#define LOGINW_CAP_CLOSE_DEVICE (1 << 11)
+
+#define LOGINW_CAP_OPEN_DEVICE (1 << 12)
+
+/*
+ * Device classes for LoginwOpenDevice
+ */
+enum LoginwDeviceClass {
+  /*
+   * evdev (/dev/input/event*), revoked on VT release
+   */
+  LoginwDeviceInput = 0,
+  /*
+   * DRM (/dev/dri/*), DRM master is dropped on VT release
+   */
+  LoginwDeviceDrm = 1,
+  /*
+   * Sound (/dev/dsp*, /dev/snd/*), kept open on VT release
+   */
+  LoginwDeviceSound = 2,
+  /*
+   * video4linux (/dev/video*), kept open on VT release
+   */
+  LoginwDeviceVideo = 3,
+  /*
+   * Raw HID (/dev/hidraw*), only where the kernel can revoke it on VT release (Linux 6.12)
+   */
+  LoginwDeviceHidraw = 4,
+  /*
+   * Framebuffer (/dev/fb*), kept open on VT release, stop drawing when deactivated
+   */
+  LoginwDeviceFramebuffer = 5,
+};
+typedef uint16_t LoginwDeviceClass;
+
 enum LoginwRequestType {
   /*
-   * bytes -> fd -- Open an input (evdev) device fd (by full path)
+   * bytes -> u64 + fd -- Open an input (evdev) device fd (by full path), returns the device id
    */
   LoginwOpenInput = 0,
   /*
-   * bytes -> fd -- Open a DRM device fd (by full path)
+   * bytes -> u64 + fd -- Open a DRM device fd (by full path), returns the device id
    */
   LoginwOpenDrm = 1,
   /*
-   * void -> u64 + fd -- Initialize a new virtual terminal, returns vt number and passes tty fd
+   * u64 -> void -- Close a device (by device id), e.g. after hotplug removal
+   */
+  LoginwCloseDevice = 2,
+  /*
+   * open_device -> u64 + fd -- Open a device of the given class (by full path), returns the device id
+   */
+  LoginwOpenDevice = 3,
+  /*
+   * u64 -> u64 + fd -- Initialize a virtual terminal (by number, must be free or loginw's own; 0 for the default),
+   * returns vt number and passes tty fd
    */
   LoginwAcquireVt = 100,
   /*
@@ -49,6 +121,10 @@ enum LoginwRequestType {
    * void -> boolean -- Checks whether hibernation is possible
    */
   LoginwCanHibernate = 303,
+  /*
+   * hello -> hello -- Exchanges protocol versions, returns the server's capabilities
+   */
+  LoginwHello = 1000,
 };
 typedef uint16_t LoginwRequestType;
 
@@ -58,13 +134,85 @@ enum LoginwResponseType {
   LoginwPassedFd = 2,
   LoginwActivated = 100,
   LoginwDeactivated = 101,
+  /*
+   * u64 -- The device with this id was revoked on VT release, its fd is dead
+   */
+  LoginwDevicePaused = 102,
+  /*
+   * u64 + fd -- The device with this id was reopened on VT acquire, use the new fd
+   */
+  LoginwDeviceResumed = 103,
 };
 typedef uint16_t LoginwResponseType;
 
+enum LoginwErrorCode {
+  /*
+   * Not an error
+   */
+  LoginwErrNone = 0,
+  /*
+   * A system call failed, sys_errno has the errno value
+   */
+  LoginwErrErrno = 1,
+  /*
+   * The path is not a device of the requested kind
+   */
+  LoginwErrNotADevice = 2,
+  /*
+   * The request needs a VT, but LoginwAcquireVt was not done yet
+   */
+  LoginwErrNoVt = 3,
+  /*
+   * The request was denied by the access policy
+   */
+  LoginwErrDenied = 4,
+  /*
+   * The request type is unknown or not implemented
+   */
+  LoginwErrUnsupported = 5,
+  /*
+   * The request data is malformed
+   */
+  LoginwErrBadData = 6,
+  /*
+   * The action is not available on this machine
+   */
+  LoginwErrUnavailable = 7,
+  /*
+   * Something else went wrong, see the message
+   */
+  LoginwErrFailed = 8,
+};
+typedef uint16_t LoginwErrorCode;
+
+typedef struct {
+  /*
+   * LOGINW_PROTOCOL_VERSION of the sender
+   */
+  uint32_t version;
+  /*
+   * LOGINW_CAP_* bits of the implemented request types (server only)
+   */
+  uint64_t capabilities;
+} LoginwHelloData;
+
+typedef struct {
+  /*
+   * A LoginwDeviceClass value
+   */
+  uint16_t device_class;
+  /*
+   * NUL-terminated full path
+   */
+  uint8_t path[126];
+} LoginwOpenDeviceData;
+
 typedef union {
   uint8_t bytes[128];
   uint64_t u64;
   bool boolean;
+  LoginwHelloData hello;
+  LoginwOpenDeviceData open_device;
 } LoginwData;
 
 typedef struct {
@@ -72,13 +220,36 @@ typedef struct {
   LoginwData dat;
 } LoginwRequest;
 
+/*
+ * The error fields occupy what used to be padding after typ,
+ * so the size and the offset of dat are the same as in version 0.
+ */
 typedef struct {
   LoginwResponseType typ;
+  /*
+   * Error category (LoginwError responses only)
+   */
+  LoginwErrorCode err;
+  /*
+   * errno value when err is LoginwErrErrno
+   */
+  int32_t sys_errno;
+  /*
+   * For LoginwError: optional NUL-terminated message in bytes
+   */
   LoginwData dat;
 } LoginwResponse;
 
 /* This is autogenerated by cbindgen. Don't modify this manually. */
 
-void _cbindgen_helper(LoginwData _a, LoginwRequestType _b, LoginwRequest _c, LoginwResponseType _d, LoginwResponse _e);
+void _cbindgen_helper(LoginwData _a,
+                      LoginwRequestType _b,
+                      LoginwRequest _c,
+                      LoginwResponseType _d,
+                      LoginwResponse _e,
+                      LoginwErrorCode _f,
+                      LoginwHelloData _g,
+                      LoginwDeviceClass _h,
+                      LoginwOpenDeviceData _i);
 
 /* This is autogenerated by cbindgen. Don't modify this manually. */
@@ -0,0 +1,62 @@
+//! Puts a VT left in graphics mode by a crashed compositor back into a usable state
+//! (text mode, keyboard mode, sane termios, VT_AUTO) and switches to a VT.
+//! For use from ssh or a hotkey daemon, needs root.
+
+extern crate loginw;
+extern crate libc;
+extern crate nix;
+extern crate pretty_env_logger;
+
+use std::{env, process};
+use nix::fcntl::{self, OFlag};
+use nix::sys::stat;
+
+use loginw::vt;
+
+const USAGE: &str = "usage: loginw-reset-console [VT [SWITCH_TO]]
+  VT        the VT to reset (default: the active one)
+  SWITCH_TO the VT to switch to afterwards (default: VT)";
+
+fn vt_arg(arg: &str) -> libc::c_int {
+    match arg.parse::<libc::c_int>() {
+        Ok(n) if n >= 1 => n,
+        _ => {
+            eprintln!("loginw-reset-console: invalid VT number {}\n{}", arg, USAGE);
+            process::exit(2);
+        },
+    }
+}
+
+fn main() {
+    // the restore steps only report failures through the log
+    if env::var_os("RUST_LOG").is_none() {
+        env::set_var("RUST_LOG", "warn");
+    }
+    pretty_env_logger::init();
+    let args: Vec<String> = env::args().collect();
+    if args.len() > 3 || args.iter().skip(1).any(|a| a.starts_with('-')) {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+    let dev_dir = fcntl::open("/dev", OFlag::O_DIRECTORY | OFlag::O_CLOEXEC, stat::Mode::empty()).expect("open /dev");
+    let vt_num = match args.get(1) {
+        Some(arg) => vt_arg(arg),
+        None => vt::active_vt(dev_dir).unwrap_or_else(|e| {
+            eprintln!("loginw-reset-console: could not get the active VT: {}", e);
+            process::exit(1);
+        }),
+    };
+    let switch_to = match args.get(2) {
+        Some(arg) => vt_arg(arg),
+        None => vt_num,
+    };
+    let tty_fd = vt::open_vt(dev_dir, vt_num).unwrap_or_else(|e| {
+        eprintln!("loginw-reset-console: could not open VT {}: {}", vt_num, e);
+        process::exit(1);
+    });
+    if !vt::restore(tty_fd, vt::DEFAULT_KB_MODE, Some(switch_to)) {
+        eprintln!("loginw-reset-console: VT {} was only partially reset", vt_num);
+        process::exit(1);
+    }
+    println!("VT {} reset, switched to VT {}", vt_num, switch_to);
+}
@@ -0,0 +1,342 @@
+//! C API over client::Client, exported by the libloginw cdylib/staticlib (see loginw.h)
+
+use std::ptr;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use libc::{self, c_char, c_int, c_void};
+use nix;
+use nix::fcntl::OFlag;
+use nix::unistd;
+use client::{Client, Error, Notification};
+use protocol::*;
+
+/// Called from loginw_dispatch, after the session was (de)activated by a VT switch.
+/// Revoked devices are paused before deactivated is called, and resumed before activated is called.
+/// A resumed device keeps its fd number (the new fd is dup'd over it, close-on-exec).
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct LoginwCallbacks {
+    pub activated: Option<extern "C" fn(client: *mut LoginwClient, userdata: *mut c_void)>,
+    pub deactivated: Option<extern "C" fn(client: *mut LoginwClient, userdata: *mut c_void)>,
+    pub device_paused: Option<extern "C" fn(client: *mut LoginwClient, fd: c_int, userdata: *mut c_void)>,
+    pub device_resumed: Option<extern "C" fn(client: *mut LoginwClient, fd: c_int, userdata: *mut c_void)>,
+}
+
+/// Opaque connection handle
+pub struct LoginwClient {
+    client: Client,
+    callbacks: LoginwCallbacks,
+    userdata: *mut c_void,
+    last_error: LoginwErrorCode,
+    /// fd returned by loginw_open_device -> server device id
+    devices: HashMap<c_int, u64>,
+}
+
+impl LoginwClient {
+    fn fd_for_device(&self, id: u64) -> Option<c_int> {
+        self.devices.iter().find(|&(_, dev_id)| *dev_id == id).map(|(fd, _)| *fd)
+    }
+
+    /// Records the error and sets errno, returns -1 for convenience
+    fn fail(&mut self, e: Error) -> c_int {
+        warn!("{}", e);
+        let (code, errno) = match e {
+            Error::NoEnv => (LoginwErrorCode::LoginwErrFailed, libc::ENOENT),
+            Error::BadPath => (LoginwErrorCode::LoginwErrBadData, libc::EINVAL),
+            Error::Ipc(_) | Error::Unexpected(_) => (LoginwErrorCode::LoginwErrFailed, libc::EIO),
+            Error::Sys(nix::Error::Sys(errno)) => (LoginwErrorCode::LoginwErrErrno, errno as c_int),
+            Error::Sys(_) => (LoginwErrorCode::LoginwErrFailed, libc::EIO),
+            Error::Server { code, errno, .. } => (code, match code {
+                LoginwErrorCode::LoginwErrErrno => errno,
+                LoginwErrorCode::LoginwErrNotADevice => libc::ENODEV,
+                LoginwErrorCode::LoginwErrNoVt => libc::ENOTTY,
+                LoginwErrorCode::LoginwErrDenied => libc::EPERM,
+                LoginwErrorCode::LoginwErrUnsupported => libc::ENOSYS,
+                LoginwErrorCode::LoginwErrBadData => libc::EINVAL,
+                LoginwErrorCode::LoginwErrUnavailable => libc::EOPNOTSUPP,
+                _ => libc::EIO,
+            }),
+        };
+        self.last_error = code;
+        set_errno(errno);
+        -1
+    }
+}
+
+#[cfg(target_os = "freebsd")]
+fn set_errno(errno: c_int) {
+    unsafe { *libc::__error() = errno };
+}
+
+#[cfg(target_os = "linux")]
+fn set_errno(errno: c_int) {
+    unsafe { *libc::__errno_location() = errno };
+}
+
+unsafe fn path_str<'a>(path: *const c_char) -> Option<&'a str> {
+    if path.is_null() {
+        return None;
+    }
+    CStr::from_ptr(path).to_str().ok()
+}
+
+/// Connects using LOGINW_FD. callbacks may be NULL. Returns NULL and sets errno on failure.
+///
+/// # Safety
+///
+/// callbacks must be NULL or point to a LoginwCallbacks, which is copied.
+#[no_mangle]
+pub unsafe extern "C" fn loginw_connect_from_env(callbacks: *const LoginwCallbacks, userdata: *mut c_void) -> *mut LoginwClient {
+    let client = match Client::from_env() {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("{}", e);
+            set_errno(libc::ENOENT);
+            return ptr::null_mut();
+        },
+    };
+    let callbacks = match callbacks.as_ref() {
+        Some(cbs) => *cbs,
+        None => LoginwCallbacks { activated: None, deactivated: None, device_paused: None, device_resumed: None },
+    };
+    Box::into_raw(Box::new(LoginwClient {
+        client,
+        callbacks,
+        userdata,
+        last_error: LoginwErrorCode::LoginwErrNone,
+        devices: HashMap::new(),
+    }))
+}
+
+/// Closes the connection and frees the handle
+///
+/// # Safety
+///
+/// client must be NULL or a handle from loginw_connect_from_env that wasn't disconnected, it can't be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn loginw_disconnect(client: *mut LoginwClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Error category of the last failed call
+///
+/// # Safety
+///
+/// client must be NULL or a handle from loginw_connect_from_env that wasn't disconnected.
+#[no_mangle]
+pub unsafe extern "C" fn loginw_last_error(client: *const LoginwClient) -> LoginwErrorCode {
+    match client.as_ref() {
+        Some(c) => c.last_error,
+        None => LoginwErrorCode::LoginwErrNone,
+    }
+}
+
+/// Opens an input or DRM device (by full path), returns the fd or -1 and sets errno
+///
+/// # Safety
+///
+/// client must be NULL or a handle from loginw_connect_from_env that wasn't disconnected, path NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn loginw_open_device(client: *mut LoginwClient, path: *const c_char) -> c_int {
+    let c = match client.as_mut() {
+        Some(c) => c,
+        None => return -1,
+    };
+    let path = match path_str(path) {
+        Some(p) => p,
+        None => return c.fail(Error::BadPath),
+    };
+    let res = if path.starts_with("/dev/dri/") {
+        c.client.open_drm(path)
+    } else {
+        c.client.open_input(path)
+    };
+    match res {
+        Ok((id, fd)) => {
+            let fd = fd.into_raw_fd();
+            c.devices.insert(fd, id);
+            fd
+        },
+        Err(e) => c.fail(e),
+    }
+}
+
+/// Opens a device of the given LoginwDeviceClass (by full path), returns the fd or -1 and sets errno.
+/// Revoked devices are paused and resumed like input devices.
+///
+/// # Safety
+///
+/// client must be NULL or a handle from loginw_connect_from_env that wasn't disconnected, path NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn loginw_open_device_class(client: *mut LoginwClient, class: u16, path: *const c_char) -> c_int {
+    let c = match client.as_mut() {
+        Some(c) => c,
+        None => return -1,
+    };
+    let class = match LoginwDeviceClass::from_u16(class) {
+        Some(cl) => cl,
+        None => return c.fail(Error::Sys(nix::Error::Sys(nix::errno::Errno::EINVAL))),
+    };
+    let path = match path_str(path) {
+        Some(p) => p,
+        None => return c.fail(Error::BadPath),
+    };
+    match c.client.open_device(class, path) {
+        Ok((id, fd)) => {
+            let fd = fd.into_raw_fd();
+            c.devices.insert(fd, id);
+            fd
+        },
+        Err(e) => c.fail(e),
+    }
+}
+
+/// Closes a device fd returned by loginw_open_device(_class), and tells loginw it's no longer used
+///
+/// # Safety
+///
+/// client must be NULL or a handle from loginw_connect_from_env that wasn't disconnected.
+#[no_mangle]
+pub unsafe extern "C" fn loginw_close_device(client: *mut LoginwClient, fd: c_int) -> c_int {
+    let c = match client.as_mut() {
+        Some(c) => c,
+        None => return -1,
+    };
+    let id = match c.devices.remove(&fd) {
+        Some(id) => id,
+        None => return c.fail(Error::Sys(nix::Error::Sys(nix::errno::Errno::EBADF))),
+    };
+    let _ = unistd::close(fd);
+    match c.client.close_device(id) {
+        Ok(()) => 0,
+        Err(e) => c.fail(e),
+    }
+}
+
+/// Sets up the VT, stores its number in vt_num (may be NULL), returns the tty fd or -1.
+/// Each call returns a new tty fd, owned by the caller, close it when done.
+///
+/// # Safety
+///
+/// client must be NULL or a handle from loginw_connect_from_env that wasn't disconnected, vt_num NULL or writable.
+#[no_mangle]
+pub unsafe extern "C" fn loginw_acquire_vt(client: *mut LoginwClient, vt_num: *mut u32) -> c_int {
+    let c = match client.as_mut() {
+        Some(c) => c,
+        None => return -1,
+    };
+    match c.client.acquire_vt() {
+        Ok((num, fd)) => {
+            if let Some(out) = vt_num.as_mut() {
+                *out = num;
+            }
+            fd.into_raw_fd()
+        },
+        Err(e) => c.fail(e),
+    }
+}
+
+/// Sets up a specific VT (0 for loginw's default), stores the acquired VT number in out_vt_num (may be NULL),
+/// returns the tty fd or -1. Each call returns a new tty fd, owned by the caller, close it when done.
+///
+/// # Safety
+///
+/// client must be NULL or a handle from loginw_connect_from_env that wasn't disconnected, out_vt_num NULL or writable.
+#[no_mangle]
+pub unsafe extern "C" fn loginw_acquire_vt_number(client: *mut LoginwClient, vt_num: u32, out_vt_num: *mut u32) -> c_int {
+    let c = match client.as_mut() {
+        Some(c) => c,
+        None => return -1,
+    };
+    match c.client.acquire_vt_number(vt_num) {
+        Ok((num, fd)) => {
+            if let Some(out) = out_vt_num.as_mut() {
+                *out = num;
+            }
+            fd.into_raw_fd()
+        },
+        Err(e) => c.fail(e),
+    }
+}
+
+/// Switches to another VT, returns 0 or -1
+///
+/// # Safety
+///
+/// client must be NULL or a handle from loginw_connect_from_env that wasn't disconnected.
+#[no_mangle]
+pub unsafe extern "C" fn loginw_switch_vt(client: *mut LoginwClient, vt_num: u32) -> c_int {
+    let c = match client.as_mut() {
+        Some(c) => c,
+        None => return -1,
+    };
+    match c.client.switch_vt(vt_num) {
+        Ok(()) => 0,
+        Err(e) => c.fail(e),
+    }
+}
+
+/// The connection fd, poll it for readability and call loginw_dispatch
+///
+/// # Safety
+///
+/// client must be NULL or a handle from loginw_connect_from_env that wasn't disconnected.
+#[no_mangle]
+pub unsafe extern "C" fn loginw_get_fd(client: *const LoginwClient) -> c_int {
+    match client.as_ref() {
+        Some(c) => c.client.as_raw_fd(),
+        None => -1,
+    }
+}
+
+/// Reads pending messages and runs the callbacks, returns the number of callbacks run or -1
+///
+/// # Safety
+///
+/// client must be NULL or a handle from loginw_connect_from_env that wasn't disconnected. The callbacks must not disconnect it.
+#[no_mangle]
+pub unsafe extern "C" fn loginw_dispatch(client: *mut LoginwClient) -> c_int {
+    // Collect first: the callbacks are allowed to call back into the API
+    let (pending, userdata) = {
+        let c = match client.as_mut() {
+            Some(c) => c,
+            None => return -1,
+        };
+        if let Err(e) = c.client.dispatch() {
+            return c.fail(e);
+        }
+        let mut pending = Vec::new();
+        while let Some(notif) = c.client.next_notification() {
+            pending.push(match notif {
+                Notification::Activated => Pending::Session(c.callbacks.activated),
+                Notification::Deactivated => Pending::Session(c.callbacks.deactivated),
+                Notification::DevicePaused(id) => match c.fd_for_device(id) {
+                    Some(fd) => Pending::Device(c.callbacks.device_paused, fd),
+                    None => continue,
+                },
+                Notification::DeviceResumed(id, new_fd) => match c.fd_for_device(id) {
+                    // new_fd is closed when dropped, the dup stays (dup2 alone would clear FD_CLOEXEC)
+                    Some(fd) if unistd::dup3(new_fd.as_raw_fd(), fd, OFlag::O_CLOEXEC).is_ok() => Pending::Device(c.callbacks.device_resumed, fd),
+                    _ => continue,
+                },
+            });
+        }
+        (pending, c.userdata)
+    };
+    for p in pending.iter() {
+        match *p {
+            Pending::Session(Some(cb)) => cb(client, userdata),
+            Pending::Device(Some(cb), fd) => cb(client, fd, userdata),
+            _ => (),
+        }
+    }
+    pending.len() as c_int
+}
+
+enum Pending {
+    Session(Option<extern "C" fn(client: *mut LoginwClient, userdata: *mut c_void)>),
+    Device(Option<extern "C" fn(client: *mut LoginwClient, fd: c_int, userdata: *mut c_void)>, c_int),
+}
@@ -0,0 +1,131 @@
+use std::ffi::OsString;
+use std::time::Duration;
+use libc;
+use respawn::Limits;
+use vt::OnExit;
+
+pub const USAGE: &str = "usage: loginw [--seatd] [--logind] [--grace-period=SECONDS] [--respawn[=CRASHES/SECONDS]] [--vt=N|current] [--exit-vt=original|stay|N] [--] command [args...]";
+
+const DEFAULT_GRACE_PERIOD: u64 = 10;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VtChoice {
+    Number(libc::c_int),
+    /// The VT loginw is started from
+    Current,
+}
+
+pub struct Options {
+    /// Also serve the seatd protocol, for libseat clients
+    pub seatd: bool,
+    /// Also serve a subset of the logind D-Bus API, for TakeDevice users
+    pub logind: bool,
+    /// How long the command gets to exit after SIGTERM/SIGINT is forwarded, before SIGKILL
+    pub grace_period: Duration,
+    /// Restart the command when it crashes, until it crashes too often
+    pub respawn: Option<Limits>,
+    /// The VT to use when the client doesn't ask for one, instead of the first free one
+    pub vt: Option<VtChoice>,
+    /// Where to leave the console when exiting
+    pub exit_vt: OnExit,
+}
+
+/// Splits the leading --options from the command to run
+pub fn parse(args: &[OsString]) -> Result<(Options, &[OsString]), String> {
+    let mut opts = Options { seatd: false, logind: false, grace_period: Duration::from_secs(DEFAULT_GRACE_PERIOD), respawn: None, vt: None, exit_vt: OnExit::Original };
+    let mut i = 1;
+    while i < args.len() {
+        let arg = args[i].to_string_lossy().into_owned();
+        if !arg.starts_with("--") {
+            break;
+        }
+        i += 1;
+        match &arg as &str {
+            "--" => break,
+            "--seatd" => opts.seatd = true,
+            "--logind" => opts.logind = true,
+            "--respawn" => opts.respawn = Some(Limits::default()),
+            _ if arg.starts_with("--respawn=") => {
+                let limits = arg["--respawn=".len()..].parse::<Limits>().map_err(|_| format!("invalid crash limit in {}", arg))?;
+                opts.respawn = Some(limits);
+            },
+            "--vt=current" => opts.vt = Some(VtChoice::Current),
+            "--exit-vt=original" => opts.exit_vt = OnExit::Original,
+            "--exit-vt=stay" => opts.exit_vt = OnExit::Stay,
+            _ if arg.starts_with("--exit-vt=") => {
+                let vt_num = arg["--exit-vt=".len()..].parse::<libc::c_int>().ok().filter(|n| *n >= 1).ok_or_else(|| format!("invalid VT in {}", arg))?;
+                opts.exit_vt = OnExit::Switch(vt_num);
+            },
+            _ if arg.starts_with("--vt=") => {
+                let vt_num = arg["--vt=".len()..].parse::<libc::c_int>().ok().filter(|n| *n >= 1).ok_or_else(|| format!("invalid VT number in {}", arg))?;
+                opts.vt = Some(VtChoice::Number(vt_num));
+            },
+            _ if arg.starts_with("--grace-period=") => {
+                let secs = arg["--grace-period=".len()..].parse::<u64>().map_err(|_| format!("invalid grace period in {}", arg))?;
+                opts.grace_period = Duration::from_secs(secs);
+            },
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    if i >= args.len() {
+        return Err("no command given".to_owned());
+    }
+    Ok((opts, &args[i..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<OsString> {
+        Some("loginw").into_iter().chain(list.iter().cloned()).map(OsString::from).collect()
+    }
+
+    #[test]
+    fn defaults() {
+        let a = args(&["sway", "-d"]);
+        let (opts, command) = parse(&a).unwrap();
+        assert!(!opts.seatd && !opts.logind && opts.respawn.is_none() && opts.vt.is_none());
+        assert_eq!(opts.grace_period, Duration::from_secs(DEFAULT_GRACE_PERIOD));
+        assert_eq!(opts.exit_vt, OnExit::Original);
+        assert_eq!(command, &a[1..]);
+    }
+
+    #[test]
+    fn options_end_at_the_command() {
+        let a = args(&["--seatd", "--logind", "--", "--respawn", "x"]);
+        let (opts, command) = parse(&a).unwrap();
+        assert!(opts.seatd && opts.logind && opts.respawn.is_none());
+        assert_eq!(command, &a[4..]);
+        let a = args(&["weston", "--seatd"]);
+        let (opts, command) = parse(&a).unwrap();
+        assert!(!opts.seatd);
+        assert_eq!(command.len(), 2);
+    }
+
+    #[test]
+    fn values() {
+        let a = args(&["--grace-period=3", "--respawn=2/30", "--vt=7", "--exit-vt=stay", "cmd"]);
+        let (opts, _) = parse(&a).unwrap();
+        assert_eq!(opts.grace_period, Duration::from_secs(3));
+        let limits = opts.respawn.unwrap();
+        assert_eq!((limits.max_crashes, limits.window), (2, Duration::from_secs(30)));
+        assert_eq!(opts.vt, Some(VtChoice::Number(7)));
+        assert_eq!(opts.exit_vt, OnExit::Stay);
+        let (opts, _) = parse(&args(&["--respawn", "--vt=current", "--exit-vt=2", "cmd"])).unwrap();
+        assert_eq!(opts.respawn.unwrap().max_crashes, Limits::default().max_crashes);
+        assert_eq!(opts.vt, Some(VtChoice::Current));
+        assert_eq!(opts.exit_vt, OnExit::Switch(2));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse(&args(&[])).is_err());
+        assert!(parse(&args(&["--seatd"])).is_err());
+        assert!(parse(&args(&["--seatd", "--"])).is_err());
+        for bad in &["--bogus", "--grace-period=", "--grace-period=-1", "--respawn=5", "--respawn=0/60",
+                     "--vt=0", "--vt=-2", "--vt=tty2", "--exit-vt=", "--exit-vt=0", "--exit-vt=back"] {
+            assert!(parse(&args(&[bad, "cmd"])).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! Client side of the loginw protocol, for compositors running under loginw
+
+use std::{env, error, fmt, result};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::os::unix::io::{RawFd, AsRawFd, FromRawFd, OwnedFd};
+use nix;
+use nix::poll::{poll, PollFd, EventFlags};
+use tiny_nix_ipc::{self, Socket};
+use protocol::*;
+
+#[derive(Debug)]
+pub enum Error {
+    /// LOGINW_FD is missing or not a number
+    NoEnv,
+    /// The path is not valid UTF-8 or doesn't fit into the request
+    BadPath,
+    Ipc(tiny_nix_ipc::errors::Error),
+    Sys(nix::Error),
+    /// The server answered with LoginwError
+    Server { code: LoginwErrorCode, errno: i32, message: String },
+    /// The server answered with something that doesn't make sense for the request
+    Unexpected(u16),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NoEnv => write!(f, "No LOGINW_FD, launch under loginw"),
+            Error::BadPath => write!(f, "Invalid or too long path"),
+            Error::Ipc(ref e) => write!(f, "{}", e),
+            Error::Sys(ref e) => write!(f, "{}", e),
+            Error::Server { code, errno, ref message } => write!(f, "{:?} (errno {}): {}", code, errno, message),
+            Error::Unexpected(typ) => write!(f, "Unexpected response type {}", typ),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<tiny_nix_ipc::errors::Error> for Error {
+    fn from(e: tiny_nix_ipc::errors::Error) -> Error {
+        Error::Ipc(e)
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// Asynchronous messages from the server, sent on VT switches
+#[derive(Debug)]
+pub enum Notification {
+    Activated,
+    Deactivated,
+    /// The device with this id was revoked, its fd is dead (sent before Deactivated)
+    DevicePaused(u64),
+    /// The device with this id was reopened, replace the dead fd (sent before Activated)
+    DeviceResumed(u64, OwnedFd),
+}
+
+/// LoginwResponse as received, before checking that the type is a known one
+#[repr(C)]
+struct RawResponse {
+    typ: u16,
+    err: u16,
+    sys_errno: i32,
+    dat: LoginwData,
+}
+
+pub struct Client {
+    sock: Socket,
+    notifications: VecDeque<Notification>,
+}
+
+impl Client {
+    /// Uses the socket passed by loginw in the LOGINW_FD environment variable
+    pub fn from_env() -> Result<Client> {
+        let fd = env::var("LOGINW_FD").map_err(|_| Error::NoEnv)?
+            .parse::<RawFd>().map_err(|_| Error::NoEnv)?;
+        Ok(unsafe { Client::from_raw_fd(fd) })
+    }
+
+    /// Uses a socket connected to loginw
+    ///
+    /// # Safety
+    ///
+    /// fd must be an open socket connected to loginw, the Client takes ownership of it.
+    pub unsafe fn from_raw_fd(fd: RawFd) -> Client {
+        Client { sock: Socket::from_raw_fd(fd), notifications: VecDeque::new() }
+    }
+
+    /// Returns the server's protocol version and LOGINW_CAP_* bits
+    pub fn hello(&mut self) -> Result<(u32, u64)> {
+        let mut req = LoginwRequest::new(LoginwRequestType::LoginwHello);
+        req.dat.hello = LoginwHelloData { version: LOGINW_PROTOCOL_VERSION, capabilities: 0 };
+        let (resp, _) = self.request(req, LoginwResponseType::LoginwDone)?;
+        let hello = unsafe { resp.dat.hello };
+        Ok((hello.version, hello.capabilities))
+    }
+
+    /// Returns the device id (for close_device) and the fd
+    pub fn open_input(&mut self, path: &str) -> Result<(u64, OwnedFd)> {
+        self.open_path(LoginwRequestType::LoginwOpenInput, path)
+    }
+
+    /// Returns the device id (for close_device) and the fd
+    pub fn open_drm(&mut self, path: &str) -> Result<(u64, OwnedFd)> {
+        self.open_path(LoginwRequestType::LoginwOpenDrm, path)
+    }
+
+    /// Opens any device class, returns the device id (for close_device) and the fd
+    pub fn open_device(&mut self, class: LoginwDeviceClass, path: &str) -> Result<(u64, OwnedFd)> {
+        let mut req = LoginwRequest::new(LoginwRequestType::LoginwOpenDevice);
+        unsafe {
+            req.dat.open_device.device_class = class as u16;
+            write_path(&mut req.dat.open_device.path, path)?;
+        }
+        self.open(req)
+    }
+
+    /// Tells the server the device is no longer used, e.g. after hotplug removal.
+    /// Close your fd too, this only closes the server's copy.
+    pub fn close_device(&mut self, id: u64) -> Result<()> {
+        let mut req = LoginwRequest::new(LoginwRequestType::LoginwCloseDevice);
+        req.dat.u64 = id;
+        self.request(req, LoginwResponseType::LoginwDone).map(drop)
+    }
+
+    /// Returns the VT number and the tty fd. Every call passes a new fd.
+    pub fn acquire_vt(&mut self) -> Result<(u32, OwnedFd)> {
+        self.acquire_vt_number(0)
+    }
+
+    /// Like acquire_vt, but asks for a specific VT, which has to be free or the one loginw was started from.
+    /// 0 means loginw's default (--vt, or the first free one).
+    pub fn acquire_vt_number(&mut self, vt_num: u32) -> Result<(u32, OwnedFd)> {
+        let mut req = LoginwRequest::new(LoginwRequestType::LoginwAcquireVt);
+        req.dat.u64 = vt_num as u64;
+        self.open(req).map(|(num, fd)| (num as u32, fd))
+    }
+
+    pub fn switch_vt(&mut self, vt_num: u32) -> Result<()> {
+        let mut req = LoginwRequest::new(LoginwRequestType::LoginwSwitchVt);
+        req.dat.u64 = vt_num as u64;
+        self.request(req, LoginwResponseType::LoginwDone).map(drop)
+    }
+
+    pub fn power_off(&mut self) -> Result<()> {
+        self.simple(LoginwRequestType::LoginwPowerOff)
+    }
+
+    pub fn reboot(&mut self) -> Result<()> {
+        self.simple(LoginwRequestType::LoginwReboot)
+    }
+
+    pub fn suspend(&mut self) -> Result<()> {
+        self.simple(LoginwRequestType::LoginwSuspend)
+    }
+
+    pub fn hibernate(&mut self) -> Result<()> {
+        self.simple(LoginwRequestType::LoginwHibernate)
+    }
+
+    pub fn can_suspend(&mut self) -> Result<bool> {
+        let (resp, _) = self.request(LoginwRequest::new(LoginwRequestType::LoginwCanSuspend), LoginwResponseType::LoginwDone)?;
+        Ok(unsafe { resp.dat.boolean })
+    }
+
+    pub fn can_hibernate(&mut self) -> Result<bool> {
+        let (resp, _) = self.request(LoginwRequest::new(LoginwRequestType::LoginwCanHibernate), LoginwResponseType::LoginwDone)?;
+        Ok(unsafe { resp.dat.boolean })
+    }
+
+    /// Reads all messages that are already available without blocking,
+    /// call when the fd becomes readable. Notifications go to the queue.
+    pub fn dispatch(&mut self) -> Result<()> {
+        while self.readable()? {
+            let (resp, fd) = self.recv()?;
+            if !self.queue_notification(&resp, fd) {
+                warn!("unsolicited response {}", resp.typ);
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes the oldest queued notification, including ones that arrived while waiting for a reply
+    pub fn next_notification(&mut self) -> Option<Notification> {
+        self.notifications.pop_front()
+    }
+
+    fn simple(&mut self, typ: LoginwRequestType) -> Result<()> {
+        self.request(LoginwRequest::new(typ), LoginwResponseType::LoginwDone).map(drop)
+    }
+
+    fn open_path(&mut self, typ: LoginwRequestType, path: &str) -> Result<(u64, OwnedFd)> {
+        let mut req = LoginwRequest::new(typ);
+        write_path(unsafe { &mut req.dat.bytes }, path)?;
+        self.open(req)
+    }
+
+    fn open(&mut self, req: LoginwRequest) -> Result<(u64, OwnedFd)> {
+        let (resp, fd) = self.request(req, LoginwResponseType::LoginwPassedFd)?;
+        let fd = fd.ok_or(Error::Unexpected(resp.typ))?;
+        Ok((unsafe { resp.dat.u64 }, unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    fn request(&mut self, req: LoginwRequest, expected: LoginwResponseType) -> Result<(RawResponse, Option<RawFd>)> {
+        self.sock.send_struct(&req, None)?;
+        loop {
+            let (resp, fd) = self.recv()?;
+            if self.queue_notification(&resp, fd) {
+                continue;
+            }
+            if resp.typ == LoginwResponseType::LoginwError as u16 {
+                return Err(server_error(&resp));
+            }
+            if resp.typ != expected as u16 {
+                return Err(Error::Unexpected(resp.typ));
+            }
+            return Ok((resp, fd));
+        }
+    }
+
+    fn recv(&mut self) -> Result<(RawResponse, Option<RawFd>)> {
+        let (resp, fds) = self.sock.recv_struct::<RawResponse, [RawFd; 1]>()?;
+        Ok((resp, fds.map(|f| f[0])))
+    }
+
+    fn queue_notification(&mut self, resp: &RawResponse, fd: Option<RawFd>) -> bool {
+        let notification = match (LoginwResponseType::from_u16(resp.typ), fd) {
+            (Some(LoginwResponseType::LoginwActivated), _) => Notification::Activated,
+            (Some(LoginwResponseType::LoginwDeactivated), _) => Notification::Deactivated,
+            (Some(LoginwResponseType::LoginwDevicePaused), _) => Notification::DevicePaused(unsafe { resp.dat.u64 }),
+            (Some(LoginwResponseType::LoginwDeviceResumed), Some(fd)) => {
+                Notification::DeviceResumed(unsafe { resp.dat.u64 }, unsafe { OwnedFd::from_raw_fd(fd) })
+            },
+            _ => return false,
+        };
+        self.notifications.push_back(notification);
+        true
+    }
+
+    fn readable(&self) -> Result<bool> {
+        let mut fds = [PollFd::new(self.sock.as_raw_fd(), EventFlags::POLLIN)];
+        poll(&mut fds, 0).map(|n| n > 0).map_err(Error::Sys)
+    }
+}
+
+impl AsRawFd for Client {
+    /// For polling, see dispatch
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+/// Leaves room for the NUL terminator
+fn write_path(bytes: &mut [u8], path: &str) -> Result<()> {
+    if path.len() >= bytes.len() {
+        return Err(Error::BadPath);
+    }
+    write!(&mut bytes[..], "{}", path).map_err(|_| Error::BadPath)
+}
+
+fn server_error(resp: &RawResponse) -> Error {
+    let bytes = unsafe { &resp.dat.bytes };
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    let message = String::from_utf8_lossy(&bytes[..end]).into_owned();
+    Error::Server { code: LoginwErrorCode::from_u16(resp.err), errno: resp.sys_errno, message }
+}
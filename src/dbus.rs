@@ -0,0 +1,672 @@
+//! Just enough of the D-Bus wire protocol to serve a peer-to-peer connection (no bus daemon):
+//! EXTERNAL authentication, and messages whose arguments are basic types.
+//! Outgoing messages can use containers, since the logind signals and properties need them.
+
+use std::{process, str};
+use std::os::unix::io::RawFd;
+use std::time::{SystemTime, UNIX_EPOCH};
+use nix;
+use nix::errno::Errno;
+use nix::unistd;
+use nix::sys::uio::IoVec;
+use nix::sys::socket::*;
+
+pub const METHOD_CALL: u8 = 1;
+pub const METHOD_RETURN: u8 = 2;
+pub const ERROR: u8 = 3;
+pub const SIGNAL: u8 = 4;
+
+const NO_REPLY_EXPECTED: u8 = 0x1;
+
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SENDER: u8 = 7;
+const FIELD_SIGNATURE: u8 = 8;
+const FIELD_UNIX_FDS: u8 = 9;
+
+const FIXED_HEADER_SIZE: usize = 16;
+/// Way more than anything the logind API needs
+const MAX_MESSAGE_SIZE: usize = 1 << 20;
+
+#[cfg(target_endian = "little")]
+const NATIVE_ENDIAN: u8 = b'l';
+#[cfg(target_endian = "big")]
+const NATIVE_ENDIAN: u8 = b'B';
+
+pub const ERR_FAILED: &str = "org.freedesktop.DBus.Error.Failed";
+pub const ERR_UNKNOWN_METHOD: &str = "org.freedesktop.DBus.Error.UnknownMethod";
+pub const ERR_UNKNOWN_OBJECT: &str = "org.freedesktop.DBus.Error.UnknownObject";
+pub const ERR_UNKNOWN_PROPERTY: &str = "org.freedesktop.DBus.Error.UnknownProperty";
+pub const ERR_INVALID_ARGS: &str = "org.freedesktop.DBus.Error.InvalidArgs";
+pub const ERR_NOT_SUPPORTED: &str = "org.freedesktop.DBus.Error.NotSupported";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    Byte(u8),
+    Bool(bool),
+    I32(i32),
+    U32(u32),
+    U64(u64),
+    Str(String),
+    Path(String),
+    Sig(String),
+    /// Index into the message's fds
+    Fd(u32),
+    Variant(Box<Arg>),
+    Struct(Vec<Arg>),
+    /// Element signature (needed for empty arrays), elements
+    Array(String, Vec<Arg>),
+    DictEntry(Box<Arg>, Box<Arg>),
+}
+
+impl Arg {
+    pub fn signature(&self) -> String {
+        match *self {
+            Arg::Byte(_) => "y".to_owned(),
+            Arg::Bool(_) => "b".to_owned(),
+            Arg::I32(_) => "i".to_owned(),
+            Arg::U32(_) => "u".to_owned(),
+            Arg::U64(_) => "t".to_owned(),
+            Arg::Str(_) => "s".to_owned(),
+            Arg::Path(_) => "o".to_owned(),
+            Arg::Sig(_) => "g".to_owned(),
+            Arg::Fd(_) => "h".to_owned(),
+            Arg::Variant(_) => "v".to_owned(),
+            Arg::Struct(ref args) => format!("({})", args.iter().map(|a| a.signature()).collect::<String>()),
+            Arg::Array(ref elem, _) => format!("a{}", elem),
+            Arg::DictEntry(ref k, ref v) => format!("{{{}{}}}", k.signature(), v.signature()),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Arg::Str(ref s) | Arg::Path(ref s) | Arg::Sig(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        match *self {
+            Arg::U32(n) => Some(n),
+            _ => None,
+        }
+    }
+}
+
+fn type_align(sig: u8) -> usize {
+    match sig {
+        b'y' | b'g' | b'v' => 1,
+        b'n' | b'q' => 2,
+        b'x' | b't' | b'd' | b'(' | b'{' => 8,
+        _ => 4,
+    }
+}
+
+pub struct Message {
+    pub typ: u8,
+    pub flags: u8,
+    pub serial: u32,
+    pub path: Option<String>,
+    pub interface: Option<String>,
+    pub member: Option<String>,
+    pub error_name: Option<String>,
+    pub reply_serial: Option<u32>,
+    pub destination: Option<String>,
+    pub sender: Option<String>,
+    pub args: Vec<Arg>,
+    /// Sent along with the message, Arg::Fd indexes into this
+    pub fds: Vec<RawFd>,
+}
+
+impl Message {
+    fn new(typ: u8) -> Message {
+        Message {
+            typ,
+            flags: 0,
+            serial: 0,
+            path: None,
+            interface: None,
+            member: None,
+            error_name: None,
+            reply_serial: None,
+            destination: None,
+            sender: None,
+            args: Vec::new(),
+            fds: Vec::new(),
+        }
+    }
+
+    pub fn method_return(call: &Message, args: Vec<Arg>) -> Message {
+        let mut msg = Message::new(METHOD_RETURN);
+        msg.reply_serial = Some(call.serial);
+        msg.destination = call.sender.clone();
+        msg.args = args;
+        msg
+    }
+
+    pub fn error(call: &Message, name: &str, text: &str) -> Message {
+        let mut msg = Message::new(ERROR);
+        msg.reply_serial = Some(call.serial);
+        msg.destination = call.sender.clone();
+        msg.error_name = Some(name.to_owned());
+        msg.args = vec![Arg::Str(text.to_owned())];
+        msg
+    }
+
+    pub fn signal(path: &str, interface: &str, member: &str, args: Vec<Arg>) -> Message {
+        let mut msg = Message::new(SIGNAL);
+        msg.path = Some(path.to_owned());
+        msg.interface = Some(interface.to_owned());
+        msg.member = Some(member.to_owned());
+        msg.args = args;
+        msg
+    }
+
+    pub fn expects_reply(&self) -> bool {
+        self.typ == METHOD_CALL && self.flags & NO_REPLY_EXPECTED == 0
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut body = Writer { buf: Vec::new() };
+        for arg in self.args.iter() {
+            body.arg(arg);
+        }
+        let signature = self.args.iter().map(|a| a.signature()).collect::<String>();
+
+        let mut fields = Vec::new();
+        let strings = [
+            (FIELD_PATH, &self.path, true),
+            (FIELD_INTERFACE, &self.interface, false),
+            (FIELD_MEMBER, &self.member, false),
+            (FIELD_ERROR_NAME, &self.error_name, false),
+            (FIELD_DESTINATION, &self.destination, false),
+            (FIELD_SENDER, &self.sender, false),
+        ];
+        for &(code, value, is_path) in strings.iter() {
+            if let Some(ref s) = *value {
+                let arg = if is_path { Arg::Path(s.clone()) } else { Arg::Str(s.clone()) };
+                fields.push(Arg::Struct(vec![Arg::Byte(code), Arg::Variant(Box::new(arg))]));
+            }
+        }
+        if let Some(serial) = self.reply_serial {
+            fields.push(Arg::Struct(vec![Arg::Byte(FIELD_REPLY_SERIAL), Arg::Variant(Box::new(Arg::U32(serial)))]));
+        }
+        if !signature.is_empty() {
+            fields.push(Arg::Struct(vec![Arg::Byte(FIELD_SIGNATURE), Arg::Variant(Box::new(Arg::Sig(signature)))]));
+        }
+        if !self.fds.is_empty() {
+            let n = self.fds.len() as u32;
+            fields.push(Arg::Struct(vec![Arg::Byte(FIELD_UNIX_FDS), Arg::Variant(Box::new(Arg::U32(n)))]));
+        }
+
+        let mut w = Writer { buf: Vec::new() };
+        w.u8(NATIVE_ENDIAN);
+        w.u8(self.typ);
+        w.u8(self.flags);
+        w.u8(1); // protocol version
+        w.u32(body.buf.len() as u32);
+        w.u32(self.serial);
+        w.arg(&Arg::Array("(yv)".to_owned(), fields));
+        w.align(8);
+        w.buf.extend_from_slice(&body.buf);
+        w.buf
+    }
+
+    fn decode(buf: &[u8]) -> Result<Message, &'static str> {
+        let mut r = Reader { buf, pos: 0, big: buf[0] == b'B' };
+        if buf[0] != b'l' && buf[0] != b'B' {
+            return Err("bad endianness marker");
+        }
+        r.pos = 1;
+        let mut msg = Message::new(r.u8()?);
+        msg.flags = r.u8()?;
+        if r.u8()? != 1 {
+            return Err("unsupported protocol version");
+        }
+        let _body_len = r.u32()?;
+        msg.serial = r.u32()?;
+        let fields_end = r.u32()? as usize + FIXED_HEADER_SIZE;
+        let mut signature = String::new();
+        while r.pos < fields_end {
+            r.align(8)?;
+            let code = r.u8()?;
+            let sig = r.sig()?;
+            if sig.len() != 1 {
+                return Err("non-basic header field");
+            }
+            let value = r.basic(sig.as_bytes()[0])?;
+            match (code, value) {
+                (FIELD_PATH, Arg::Path(s)) => msg.path = Some(s),
+                (FIELD_INTERFACE, Arg::Str(s)) => msg.interface = Some(s),
+                (FIELD_MEMBER, Arg::Str(s)) => msg.member = Some(s),
+                (FIELD_ERROR_NAME, Arg::Str(s)) => msg.error_name = Some(s),
+                (FIELD_REPLY_SERIAL, Arg::U32(n)) => msg.reply_serial = Some(n),
+                (FIELD_DESTINATION, Arg::Str(s)) => msg.destination = Some(s),
+                (FIELD_SENDER, Arg::Str(s)) => msg.sender = Some(s),
+                (FIELD_SIGNATURE, Arg::Sig(s)) => signature = s,
+                _ => (), // unknown fields must be ignored, fds aren't accepted from clients
+            }
+        }
+        r.align(8)?;
+        for t in signature.bytes() {
+            msg.args.push(r.basic(t)?);
+        }
+        Ok(msg)
+    }
+}
+
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    /// n is a power of two
+    fn align(&mut self, n: usize) {
+        while self.buf.len() & (n - 1) != 0 {
+            self.buf.push(0);
+        }
+    }
+
+    fn u8(&mut self, x: u8) {
+        self.buf.push(x);
+    }
+
+    fn u32(&mut self, x: u32) {
+        self.align(4);
+        self.buf.extend_from_slice(&x.to_ne_bytes());
+    }
+
+    fn string(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.buf.extend_from_slice(s.as_bytes());
+        self.buf.push(0);
+    }
+
+    fn sig(&mut self, s: &str) {
+        self.u8(s.len() as u8);
+        self.buf.extend_from_slice(s.as_bytes());
+        self.buf.push(0);
+    }
+
+    fn arg(&mut self, arg: &Arg) {
+        match *arg {
+            Arg::Byte(x) => self.u8(x),
+            Arg::Bool(x) => self.u32(x as u32),
+            Arg::I32(x) => self.u32(x as u32),
+            Arg::U32(x) | Arg::Fd(x) => self.u32(x),
+            Arg::U64(x) => {
+                self.align(8);
+                self.buf.extend_from_slice(&x.to_ne_bytes());
+            },
+            Arg::Str(ref s) | Arg::Path(ref s) => self.string(s),
+            Arg::Sig(ref s) => self.sig(s),
+            Arg::Variant(ref inner) => {
+                self.sig(&inner.signature());
+                self.arg(inner);
+            },
+            Arg::Struct(ref args) => {
+                self.align(8);
+                for a in args.iter() {
+                    self.arg(a);
+                }
+            },
+            Arg::Array(ref elem, ref args) => {
+                self.u32(0);
+                let len_pos = self.buf.len() - 4;
+                // the padding before the first element doesn't count towards the length
+                self.align(type_align(elem.as_bytes()[0]));
+                let start = self.buf.len();
+                for a in args.iter() {
+                    self.arg(a);
+                }
+                let len = (self.buf.len() - start) as u32;
+                self.buf[len_pos..len_pos + 4].copy_from_slice(&len.to_ne_bytes());
+            },
+            Arg::DictEntry(ref k, ref v) => {
+                self.align(8);
+                self.arg(k);
+                self.arg(v);
+            },
+        }
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    big: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], &'static str> {
+        if self.pos + n > self.buf.len() {
+            return Err("truncated message");
+        }
+        let res = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(res)
+    }
+
+    fn align(&mut self, n: usize) -> Result<(), &'static str> {
+        let pad = (n - self.pos % n) % n;
+        self.take(pad).map(drop)
+    }
+
+    fn u8(&mut self) -> Result<u8, &'static str> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, &'static str> {
+        self.align(4)?;
+        let mut x = [0u8; 4];
+        x.copy_from_slice(self.take(4)?);
+        Ok(if self.big { u32::from_be_bytes(x) } else { u32::from_le_bytes(x) })
+    }
+
+    fn u64(&mut self) -> Result<u64, &'static str> {
+        self.align(8)?;
+        let mut x = [0u8; 8];
+        x.copy_from_slice(self.take(8)?);
+        Ok(if self.big { u64::from_be_bytes(x) } else { u64::from_le_bytes(x) })
+    }
+
+    fn bytes_str(&mut self, len: usize) -> Result<String, &'static str> {
+        let s = str::from_utf8(self.take(len)?).map_err(|_| "invalid UTF-8")?.to_owned();
+        if self.u8()? != 0 {
+            return Err("string not NUL-terminated");
+        }
+        Ok(s)
+    }
+
+    fn string(&mut self) -> Result<String, &'static str> {
+        let len = self.u32()? as usize;
+        self.bytes_str(len)
+    }
+
+    fn sig(&mut self) -> Result<String, &'static str> {
+        let len = self.u8()? as usize;
+        self.bytes_str(len)
+    }
+
+    fn basic(&mut self, typ: u8) -> Result<Arg, &'static str> {
+        Ok(match typ {
+            b'y' => Arg::Byte(self.u8()?),
+            b'b' => Arg::Bool(self.u32()? != 0),
+            b'i' => Arg::I32(self.u32()? as i32),
+            b'u' => Arg::U32(self.u32()?),
+            b't' => Arg::U64(self.u64()?),
+            b's' => Arg::Str(self.string()?),
+            b'o' => Arg::Path(self.string()?),
+            b'g' => Arg::Sig(self.sig()?),
+            _ => return Err("unsupported argument type"),
+        })
+    }
+}
+
+/// Returns the total size of the message at the start of buf, if the header is complete
+fn message_size(buf: &[u8]) -> Option<usize> {
+    if buf.len() < FIXED_HEADER_SIZE {
+        return None;
+    }
+    let mut r = Reader { buf, pos: 4, big: buf[0] == b'B' };
+    let body_len = r.u32().ok()? as usize;
+    r.pos = 12;
+    let fields_len = r.u32().ok()? as usize;
+    let header_len = (FIXED_HEADER_SIZE + fields_len + 7) & !7;
+    Some(header_len + body_len)
+}
+
+pub struct Connection {
+    pub fd: RawFd,
+    /// Sender of everything we send, since there's no bus to assign one
+    name: &'static str,
+    buf: Vec<u8>,
+    guid: String,
+    authenticated: bool,
+    unix_fds: bool,
+    next_serial: u32,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = unistd::close(self.fd);
+    }
+}
+
+impl Connection {
+    /// Takes ownership of an accepted, nonblocking fd
+    pub fn new(fd: RawFd, name: &'static str) -> Connection {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Connection {
+            fd,
+            name,
+            buf: Vec::new(),
+            guid: format!("{:08x}{:08x}{:016x}", process::id(), now.subsec_nanos(), now.as_secs()),
+            authenticated: false,
+            unix_fds: false,
+            next_serial: 1,
+        }
+    }
+
+    /// Reads what's available into the buffer, returns false when the client is gone
+    pub fn fill(&mut self) -> bool {
+        let mut chunk = [0u8; 4096];
+        loop {
+            match unistd::read(self.fd, &mut chunk) {
+                Ok(0) => return false,
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(nix::Error::Sys(Errno::EAGAIN)) => return true,
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                Err(e) => {
+                    warn!("D-Bus client read: {}", e);
+                    return false;
+                },
+            }
+        }
+    }
+
+    /// Runs the authentication exchange, then takes the next complete message from the buffer.
+    /// An error means the client is broken and should be disconnected.
+    pub fn next_message(&mut self) -> Result<Option<Message>, &'static str> {
+        while !self.authenticated {
+            if self.buf.first() == Some(&0) {
+                // credentials byte, we go by the socket's directory permissions instead
+                self.buf.remove(0);
+            }
+            let end = match self.buf.windows(2).position(|w| w == b"\r\n") {
+                Some(end) => end,
+                None if self.buf.len() > 1024 => return Err("auth line too long"),
+                None => return Ok(None),
+            };
+            let line = String::from_utf8_lossy(&self.buf[..end]).into_owned();
+            self.buf.drain(..end + 2);
+            self.auth_line(&line);
+        }
+        let size = match message_size(&self.buf) {
+            Some(size) if size > MAX_MESSAGE_SIZE => return Err("message too big"),
+            Some(size) if size <= self.buf.len() => size,
+            _ => return Ok(None),
+        };
+        let msg = Message::decode(&self.buf[..size]);
+        self.buf.drain(..size);
+        msg.map(Some)
+    }
+
+    fn auth_line(&mut self, line: &str) {
+        debug!("D-Bus auth: {}", line);
+        let reply = if line.starts_with("AUTH EXTERNAL ") || line.starts_with("DATA") {
+            format!("OK {}\r\n", self.guid)
+        } else if line == "AUTH EXTERNAL" {
+            "DATA\r\n".to_owned()
+        } else if line == "NEGOTIATE_UNIX_FD" {
+            self.unix_fds = true;
+            "AGREE_UNIX_FD\r\n".to_owned()
+        } else if line == "BEGIN" {
+            self.authenticated = true;
+            return;
+        } else if line.starts_with("AUTH") || line == "CANCEL" || line.starts_with("ERROR") {
+            "REJECTED EXTERNAL\r\n".to_owned()
+        } else {
+            "ERROR\r\n".to_owned()
+        };
+        self.write_all(reply.as_bytes(), &[]);
+    }
+
+    pub fn send(&mut self, mut msg: Message) {
+        if !msg.fds.is_empty() && !self.unix_fds {
+            error!("D-Bus client did not negotiate fd passing");
+            return;
+        }
+        msg.serial = self.next_serial;
+        self.next_serial = self.next_serial.wrapping_add(1).max(1);
+        msg.sender = Some(self.name.to_owned());
+        let data = msg.encode();
+        self.write_all(&data, &msg.fds);
+    }
+
+    fn write_all(&mut self, data: &[u8], fds: &[RawFd]) {
+        let cmsgs = if fds.is_empty() { vec![] } else { vec![ControlMessage::ScmRights(fds)] };
+        if let Err(e) = sendmsg(self.fd, &[IoVec::from_slice(data)], &cmsgs, MsgFlags::empty(), None) {
+            error!("D-Bus client sendmsg: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(args: Vec<Arg>) -> Message {
+        let mut msg = Message::new(METHOD_CALL);
+        msg.serial = 7;
+        msg.path = Some("/org/freedesktop/login1/session/auto".to_owned());
+        msg.interface = Some("org.freedesktop.login1.Session".to_owned());
+        msg.member = Some("TakeDevice".to_owned());
+        msg.destination = Some("org.freedesktop.login1".to_owned());
+        msg.args = args;
+        msg
+    }
+
+    /// A connection with the client's end of a socketpair
+    fn connection() -> (Connection, RawFd) {
+        let flags = SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK;
+        let (ours, theirs) = socketpair(AddressFamily::Unix, SockType::Stream, None, flags).unwrap();
+        (Connection::new(ours, "org.freedesktop.login1"), theirs)
+    }
+
+    fn read_all(fd: RawFd) -> Vec<u8> {
+        let mut buf = [0u8; 4096];
+        let n = unistd::read(fd, &mut buf).unwrap();
+        buf[..n].to_vec()
+    }
+
+    #[test]
+    fn round_trips_basic_args() {
+        let args = vec![
+            Arg::U32(226),
+            Arg::U32(0),
+            Arg::Byte(3),
+            Arg::Bool(true),
+            Arg::I32(-5),
+            Arg::U64(1 << 40),
+            Arg::Str("seat0".to_owned()),
+            Arg::Path("/org/freedesktop/login1".to_owned()),
+            Arg::Sig("a{sv}".to_owned()),
+        ];
+        let data = call(args.clone()).encode();
+        assert_eq!(message_size(&data), Some(data.len()));
+        let msg = Message::decode(&data).unwrap();
+        assert_eq!(msg.typ, METHOD_CALL);
+        assert_eq!(msg.serial, 7);
+        assert_eq!(msg.path.as_ref().map(|s| s as &str), Some("/org/freedesktop/login1/session/auto"));
+        assert_eq!(msg.interface.as_ref().map(|s| s as &str), Some("org.freedesktop.login1.Session"));
+        assert_eq!(msg.member.as_ref().map(|s| s as &str), Some("TakeDevice"));
+        assert_eq!(msg.destination.as_ref().map(|s| s as &str), Some("org.freedesktop.login1"));
+        assert_eq!(msg.args, args);
+        assert!(msg.expects_reply());
+    }
+
+    #[test]
+    fn replies_refer_to_the_call() {
+        let mut call = call(vec![]);
+        call.sender = Some(":1.0".to_owned());
+        let reply = Message::decode(&Message::error(&call, ERR_INVALID_ARGS, "nope").encode()).unwrap();
+        assert_eq!(reply.typ, ERROR);
+        assert_eq!(reply.reply_serial, Some(7));
+        assert_eq!(reply.destination.as_ref().map(|s| s as &str), Some(":1.0"));
+        assert_eq!(reply.error_name.as_ref().map(|s| s as &str), Some(ERR_INVALID_ARGS));
+        assert_eq!(reply.args, vec![Arg::Str("nope".to_owned())]);
+    }
+
+    #[test]
+    fn encodes_containers() {
+        // the padding before the first element is not part of the array length
+        let mut w = Writer { buf: Vec::new() };
+        w.arg(&Arg::Array("t".to_owned(), vec![Arg::U64(1)]));
+        assert_eq!(w.buf.len(), 16);
+        assert_eq!(w.buf[..4], 8u32.to_ne_bytes());
+        assert_eq!(w.buf[8..], 1u64.to_ne_bytes());
+
+        let props = Arg::Array("{sv}".to_owned(), vec![
+            Arg::DictEntry(Box::new(Arg::Str("Active".to_owned())), Box::new(Arg::Variant(Box::new(Arg::Bool(true))))),
+        ]);
+        assert_eq!(props.signature(), "a{sv}");
+        let mut w = Writer { buf: Vec::new() };
+        w.arg(&props);
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&20u32.to_ne_bytes());
+        expected.extend_from_slice(&[0; 4]);
+        expected.extend_from_slice(&6u32.to_ne_bytes());
+        expected.extend_from_slice(b"Active\0");
+        expected.extend_from_slice(&[1, b'b', 0, 0, 0]);
+        expected.extend_from_slice(&1u32.to_ne_bytes());
+        assert_eq!(w.buf, expected);
+    }
+
+    #[test]
+    fn rejects_broken_messages() {
+        let data = call(vec![Arg::Str("seat0".to_owned())]).encode();
+        let mut bad = data.clone();
+        bad[0] = b'x';
+        assert!(Message::decode(&bad).is_err());
+        let mut bad = data.clone();
+        bad[3] = 2;
+        assert!(Message::decode(&bad).is_err());
+        assert!(Message::decode(&data[..data.len() - 3]).is_err());
+    }
+
+    #[test]
+    fn authenticates_then_reads_messages() {
+        let (mut conn, theirs) = connection();
+        let mut input = b"\0AUTH EXTERNAL 30\r\nNEGOTIATE_UNIX_FD\r\nBEGIN\r\n".to_vec();
+        let data = call(vec![Arg::U32(13), Arg::U32(64)]).encode();
+        input.extend_from_slice(&data[..20]);
+        unistd::write(theirs, &input).unwrap();
+        assert!(conn.fill());
+        assert!(conn.next_message().unwrap().is_none());
+        let replies = String::from_utf8(read_all(theirs)).unwrap();
+        assert_eq!(replies, format!("OK {}\r\nAGREE_UNIX_FD\r\n", conn.guid));
+        unistd::write(theirs, &data[20..]).unwrap();
+        assert!(conn.fill());
+        let msg = conn.next_message().unwrap().unwrap();
+        assert_eq!(msg.args, vec![Arg::U32(13), Arg::U32(64)]);
+        assert!(conn.next_message().unwrap().is_none());
+        unistd::close(theirs).unwrap();
+        assert!(!conn.fill());
+    }
+
+    #[test]
+    fn rejects_oversized_messages() {
+        let (mut conn, theirs) = connection();
+        conn.authenticated = true;
+        let mut data = call(vec![]).encode();
+        data[4..8].copy_from_slice(&(MAX_MESSAGE_SIZE as u32).to_ne_bytes());
+        conn.buf.extend_from_slice(&data);
+        assert!(conn.next_message().is_err());
+        let _ = unistd::close(theirs);
+    }
+}
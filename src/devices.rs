@@ -0,0 +1,461 @@
+//! Finding and identifying device nodes by device number, and tracking the ones handed out
+
+use std::collections::{btree_map, BTreeMap};
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use std::os::unix::io::RawFd;
+use libc;
+use nix;
+use nix::errno::Errno;
+use nix::fcntl::{self, AtFlags, OFlag};
+use nix::sys::stat;
+use protocol::LoginwDeviceClass;
+
+/// Directories under /dev that can contain devices handed out to the session, "" is /dev itself
+/// (on FreeBSD, the dri/ entries are symlinks to drm/)
+const SEARCH_DIRS: &[&str] = &["input", "dri", "drm", "snd", ""];
+
+/// Same as the kernel's limit on symlinks in one lookup
+const MAX_SYMLINKS: usize = 32;
+
+#[cfg(target_os = "linux")]
+const INPUT_MAJOR: u32 = 13;
+#[cfg(target_os = "linux")]
+const DRM_MAJOR: u32 = 226;
+#[cfg(target_os = "linux")]
+const OSS_MAJOR: u32 = 14;
+#[cfg(target_os = "linux")]
+const ALSA_MAJOR: u32 = 116;
+#[cfg(target_os = "linux")]
+const V4L_MAJOR: u32 = 81;
+#[cfg(target_os = "linux")]
+const FB_MAJOR: u32 = 29;
+
+#[cfg(target_os = "freebsd")]
+pub fn major(dev: libc::dev_t) -> u32 {
+    let dev = dev as u64;
+    (((dev >> 32) & 0xffff_ff00) | ((dev >> 8) & 0xff)) as u32
+}
+
+#[cfg(target_os = "freebsd")]
+pub fn minor(dev: libc::dev_t) -> u32 {
+    let dev = dev as u64;
+    (((dev >> 24) & 0xff00) | (dev & 0xffff_00ff)) as u32
+}
+
+#[cfg(target_os = "linux")]
+pub fn major(dev: libc::dev_t) -> u32 {
+    stat::major(dev) as u32
+}
+
+#[cfg(target_os = "linux")]
+pub fn minor(dev: libc::dev_t) -> u32 {
+    stat::minor(dev) as u32
+}
+
+/// Resolves a path under /dev into a path relative to /dev without symlinks,
+/// without ever leaving /dev (even temporarily, so this works in capability mode)
+pub fn resolve(dev_dir: RawFd, path: &str) -> Option<String> {
+    if !path.starts_with("/dev/") {
+        return None;
+    }
+    let mut todo: VecDeque<String> = path["/dev/".len()..].split('/').map(|c| c.to_owned()).collect();
+    let mut resolved: Vec<String> = Vec::new();
+    let mut links = 0;
+    let mut buf = [0u8; libc::PATH_MAX as usize];
+    while let Some(comp) = todo.pop_front() {
+        match &comp as &str {
+            "" | "." => continue,
+            ".." => {
+                resolved.pop()?;
+                continue;
+            },
+            _ => (),
+        }
+        let candidate = if resolved.is_empty() { comp.clone() } else { format!("{}/{}", resolved.join("/"), comp) };
+        let target = match fcntl::readlinkat(dev_dir, &candidate as &str, &mut buf) {
+            Ok(target) => target.to_str()?.to_owned(),
+            Err(nix::Error::Sys(Errno::EINVAL)) => {
+                // not a symlink
+                resolved.push(comp);
+                continue;
+            },
+            Err(_) => return None,
+        };
+        links += 1;
+        if links > MAX_SYMLINKS {
+            return None;
+        }
+        let target = if target.starts_with('/') {
+            if !target.starts_with("/dev/") {
+                return None;
+            }
+            resolved.clear();
+            target["/dev/".len()..].to_owned()
+        } else {
+            target
+        };
+        for c in target.split('/').rev() {
+            todo.push_front(c.to_owned());
+        }
+    }
+    if resolved.is_empty() {
+        return None;
+    }
+    Some(resolved.join("/"))
+}
+
+#[cfg(target_os = "linux")]
+fn is_kind(kind: DeviceKind, name: &str, dev: libc::dev_t) -> bool {
+    match kind {
+        DeviceKind::Input => major(dev) == INPUT_MAJOR && name.starts_with("input/event"),
+        DeviceKind::Drm => major(dev) == DRM_MAJOR && (name.starts_with("dri/card") || name.starts_with("dri/renderD")),
+        DeviceKind::Sound => {
+            (major(dev) == ALSA_MAJOR && name.starts_with("snd/")) || (major(dev) == OSS_MAJOR && name.starts_with("dsp"))
+        },
+        DeviceKind::Video => major(dev) == V4L_MAJOR && name.starts_with("video"),
+        // hidraw has a dynamic major. Not uhid: it creates devices and can't be revoked
+        DeviceKind::Hidraw => name.starts_with("hidraw"),
+        DeviceKind::Framebuffer => major(dev) == FB_MAJOR && name.starts_with("fb"),
+    }
+}
+
+/// Device numbers are dynamic on FreeBSD, but devfs names are set by the drivers
+#[cfg(target_os = "freebsd")]
+fn is_kind(kind: DeviceKind, name: &str, _dev: libc::dev_t) -> bool {
+    match kind {
+        DeviceKind::Input => name.starts_with("input/event"),
+        DeviceKind::Drm => name.starts_with("drm/") || name.starts_with("dri/"),
+        DeviceKind::Sound => name.starts_with("dsp") || name.starts_with("snd/"),
+        DeviceKind::Video => name.starts_with("video"),
+        DeviceKind::Hidraw => name.starts_with("hidraw"),
+        DeviceKind::Framebuffer => name.starts_with("fb"),
+    }
+}
+
+/// Resolves the path and returns the name relative to /dev and the device number, if it's a character device
+fn char_device(dev_dir: RawFd, path: &str) -> Option<(String, libc::dev_t)> {
+    let name = resolve(dev_dir, path)?;
+    let st = stat::fstatat(dev_dir, &name as &str, AtFlags::AT_SYMLINK_NOFOLLOW).ok()?;
+    if st.st_mode & libc::S_IFMT != libc::S_IFCHR {
+        return None;
+    }
+    Some((name, st.st_rdev))
+}
+
+/// Resolves the path and checks that it's a character device of the given kind, without opening it.
+/// Returns the name relative to /dev and the device number, to compare with the opened fd.
+pub fn lookup(dev_dir: RawFd, kind: DeviceKind, path: &str) -> Option<(String, libc::dev_t)> {
+    match char_device(dev_dir, path) {
+        Some((name, dev)) if is_kind(kind, &name, dev) => Some((name, dev)),
+        _ => {
+            debug!("{} is not a {:?} device", path, kind);
+            None
+        },
+    }
+}
+
+/// For the frontends that open devices by path only
+pub fn detect(dev_dir: RawFd, path: &str) -> Option<DeviceKind> {
+    let (name, dev) = char_device(dev_dir, path)?;
+    DeviceKind::ALL.iter().cloned().find(|kind| is_kind(*kind, &name, dev))
+}
+
+/// Returns the full path (e.g. /dev/input/event3) of the character device with the given number
+pub fn find_by_number(dev_dir: RawFd, maj: u32, min: u32) -> nix::Result<String> {
+    for sub in SEARCH_DIRS.iter() {
+        let dir_fd = match fcntl::openat(dev_dir, if sub.is_empty() { "." } else { *sub }, OFlag::O_DIRECTORY | OFlag::O_CLOEXEC, stat::Mode::empty()) {
+            Ok(fd) => fd,
+            Err(_) => continue,
+        };
+        let dir = unsafe { libc::fdopendir(dir_fd) };
+        if dir.is_null() {
+            let _ = nix::unistd::close(dir_fd);
+            continue;
+        }
+        let mut found = None;
+        loop {
+            let ent = unsafe { libc::readdir(dir) };
+            if ent.is_null() {
+                break;
+            }
+            let name = unsafe { CStr::from_ptr((*ent).d_name.as_ptr()) };
+            let name = match name.to_str() {
+                Ok(n) if !n.starts_with('.') => n,
+                _ => continue,
+            };
+            let st = match stat::fstatat(dir_fd, name, AtFlags::AT_SYMLINK_NOFOLLOW) {
+                Ok(st) => st,
+                Err(_) => continue,
+            };
+            if st.st_mode & libc::S_IFMT == libc::S_IFCHR && major(st.st_rdev) == maj && minor(st.st_rdev) == min {
+                found = Some(if sub.is_empty() { format!("/dev/{}", name) } else { format!("/dev/{}/{}", sub, name) });
+                break;
+            }
+        }
+        unsafe { libc::closedir(dir) };
+        if let Some(path) = found {
+            debug!("device {}:{} is {}", maj, min, path);
+            return Ok(path);
+        }
+    }
+    Err(nix::Error::Sys(Errno::ENODEV))
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DeviceKind {
+    Input,
+    Drm,
+    Sound,
+    Video,
+    Hidraw,
+    Framebuffer,
+}
+
+/// What happens to a device when the VT is released
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Release {
+    /// Revoked (the fd dies) and reopened on acquire
+    Revoke,
+    /// DRM master dropped, set again on acquire
+    DropMaster,
+    /// Left alone, the client should stop using it while deactivated
+    Keep,
+}
+
+impl DeviceKind {
+    pub const ALL: &'static [DeviceKind] = &[
+        DeviceKind::Input,
+        DeviceKind::Drm,
+        DeviceKind::Sound,
+        DeviceKind::Video,
+        DeviceKind::Hidraw,
+        DeviceKind::Framebuffer,
+    ];
+
+    /// As used in the policy file
+    pub fn from_name(name: &str) -> Option<DeviceKind> {
+        match name {
+            "input" => Some(DeviceKind::Input),
+            "drm" => Some(DeviceKind::Drm),
+            "sound" => Some(DeviceKind::Sound),
+            "video" => Some(DeviceKind::Video),
+            "hidraw" => Some(DeviceKind::Hidraw),
+            "fb" => Some(DeviceKind::Framebuffer),
+            _ => None,
+        }
+    }
+
+    pub fn from_class(class: LoginwDeviceClass) -> DeviceKind {
+        match class {
+            LoginwDeviceClass::LoginwDeviceInput => DeviceKind::Input,
+            LoginwDeviceClass::LoginwDeviceDrm => DeviceKind::Drm,
+            LoginwDeviceClass::LoginwDeviceSound => DeviceKind::Sound,
+            LoginwDeviceClass::LoginwDeviceVideo => DeviceKind::Video,
+            LoginwDeviceClass::LoginwDeviceHidraw => DeviceKind::Hidraw,
+            LoginwDeviceClass::LoginwDeviceFramebuffer => DeviceKind::Framebuffer,
+        }
+    }
+
+    /// Sound, cameras and framebuffers have no revoke ioctl
+    pub fn release(self) -> Release {
+        match self {
+            DeviceKind::Input | DeviceKind::Hidraw => Release::Revoke,
+            DeviceKind::Drm => Release::DropMaster,
+            DeviceKind::Sound | DeviceKind::Video | DeviceKind::Framebuffer => Release::Keep,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DrmNode {
+    /// cardN, can do modesetting (with master)
+    Primary,
+    /// renderDN, rendering only
+    Render,
+}
+
+impl DrmNode {
+    /// As used in the policy file
+    pub fn from_name(name: &str) -> Option<DrmNode> {
+        match name {
+            "primary" => Some(DrmNode::Primary),
+            "render" => Some(DrmNode::Render),
+            _ => None,
+        }
+    }
+}
+
+/// Render nodes start at minor 128 (renderD128), name is relative to /dev as returned by lookup
+#[cfg(target_os = "linux")]
+pub fn drm_node(_name: &str, dev: libc::dev_t) -> DrmNode {
+    if minor(dev) >= 128 { DrmNode::Render } else { DrmNode::Primary }
+}
+
+/// The devfs nodes are drm/N, with render nodes starting at 128 like on Linux
+#[cfg(target_os = "freebsd")]
+pub fn drm_node(name: &str, _dev: libc::dev_t) -> DrmNode {
+    let num = name.trim_start_matches("drm/").parse::<u32>().unwrap_or(0);
+    if name.starts_with("dri/renderD") || num >= 128 { DrmNode::Render } else { DrmNode::Primary }
+}
+
+/// Which protocol a device was opened through, each one notifies its own client and only that one can close it
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Frontend {
+    Native,
+    Seatd,
+    Logind,
+}
+
+/// A device handed out to a client
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub path: String,
+    pub major: u32,
+    pub minor: u32,
+    pub kind: DeviceKind,
+    /// For DRM devices, render nodes have no master to set or drop
+    pub drm_node: Option<DrmNode>,
+    pub owner: Frontend,
+    /// Our copy of the fd, None once an input device was revoked on VT release
+    pub fd: Option<RawFd>,
+}
+
+/// Open devices by server-issued id, ids are never reused
+pub struct DeviceTable {
+    devices: BTreeMap<u64, Device>,
+    next_id: u64,
+}
+
+impl DeviceTable {
+    pub fn new() -> DeviceTable {
+        DeviceTable { devices: BTreeMap::new(), next_id: 1 }
+    }
+
+    pub fn insert(&mut self, dev: Device) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        debug!("device {} is {:?}", id, dev);
+        self.devices.insert(id, dev);
+        id
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Device> {
+        self.devices.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut Device> {
+        self.devices.get_mut(&id)
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<Device> {
+        self.devices.remove(&id)
+    }
+
+    pub fn iter(&self) -> btree_map::Iter<'_, u64, Device> {
+        self.devices.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> btree_map::IterMut<'_, u64, Device> {
+        self.devices.iter_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+
+    fn device(path: &str, owner: Frontend) -> Device {
+        Device { path: path.to_owned(), major: 13, minor: 64, kind: DeviceKind::Input, drm_node: None, owner, fd: None }
+    }
+
+    #[test]
+    fn ids_are_not_reused() {
+        let mut table = DeviceTable::new();
+        let first = table.insert(device("/dev/input/event0", Frontend::Native));
+        let second = table.insert(device("/dev/input/event1", Frontend::Seatd));
+        assert!(first > 0 && second > first);
+        assert_eq!(table.remove(second).map(|d| d.owner), Some(Frontend::Seatd));
+        assert!(table.remove(second).is_none());
+        assert!(table.get(second).is_none());
+        let third = table.insert(device("/dev/input/event1", Frontend::Logind));
+        assert!(third > second);
+        assert_eq!(table.get(first).map(|d| &d.path as &str), Some("/dev/input/event0"));
+    }
+
+    #[test]
+    fn iterates_in_id_order() {
+        let mut table = DeviceTable::new();
+        let ids: Vec<u64> = (0..3).map(|i| table.insert(device(&format!("/dev/input/event{}", i), Frontend::Native))).collect();
+        for (_, dev) in table.iter_mut() {
+            dev.fd = Some(3);
+        }
+        table.get_mut(ids[1]).unwrap().fd = None;
+        let open: Vec<u64> = table.iter().filter(|&(_, dev)| dev.fd.is_some()).map(|(id, _)| *id).collect();
+        assert_eq!(open, vec![ids[0], ids[2]]);
+    }
+    /// A directory standing in for /dev, removed on drop
+    struct FakeDev {
+        path: PathBuf,
+        fd: RawFd,
+    }
+
+    impl FakeDev {
+        fn new(name: &str) -> FakeDev {
+            let path = env::temp_dir().join(format!("loginw-test-{}-{}", process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(path.join("input/by-path")).unwrap();
+            fs::create_dir_all(path.join("dri")).unwrap();
+            fs::write(path.join("input/event0"), b"").unwrap();
+            fs::write(path.join("dri/card0"), b"").unwrap();
+            symlink("../event0", path.join("input/by-path/kbd")).unwrap();
+            symlink("/dev/dri/card0", path.join("dri/by-abs")).unwrap();
+            symlink("../../etc/passwd", path.join("input/escape")).unwrap();
+            symlink("/etc/passwd", path.join("input/abs-escape")).unwrap();
+            symlink("/dev/../etc/passwd", path.join("input/abs-dotdot")).unwrap();
+            symlink("loop", path.join("loop")).unwrap();
+            let fd = fcntl::open(&path, OFlag::O_DIRECTORY | OFlag::O_CLOEXEC, stat::Mode::empty()).unwrap();
+            FakeDev { path, fd }
+        }
+    }
+
+    impl Drop for FakeDev {
+        fn drop(&mut self) {
+            let _ = nix::unistd::close(self.fd);
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    #[test]
+    fn resolves_inside_dev() {
+        let dev = FakeDev::new("inside");
+        assert_eq!(resolve(dev.fd, "/dev/input/event0"), Some("input/event0".to_owned()));
+        assert_eq!(resolve(dev.fd, "/dev//input/./event0"), Some("input/event0".to_owned()));
+        assert_eq!(resolve(dev.fd, "/dev/dri/../input/event0"), Some("input/event0".to_owned()));
+        assert_eq!(resolve(dev.fd, "/dev/input/by-path/kbd"), Some("input/event0".to_owned()));
+        assert_eq!(resolve(dev.fd, "/dev/dri/by-abs"), Some("dri/card0".to_owned()));
+    }
+
+    #[test]
+    fn rejects_escapes() {
+        let dev = FakeDev::new("escapes");
+        assert_eq!(resolve(dev.fd, "/etc/passwd"), None);
+        assert_eq!(resolve(dev.fd, "dev/input/event0"), None);
+        assert_eq!(resolve(dev.fd, "/dev/../etc/passwd"), None);
+        assert_eq!(resolve(dev.fd, "/dev/input/../../etc/passwd"), None);
+        assert_eq!(resolve(dev.fd, "/dev/input/escape"), None);
+        assert_eq!(resolve(dev.fd, "/dev/input/abs-escape"), None);
+        assert_eq!(resolve(dev.fd, "/dev/input/abs-dotdot"), None);
+    }
+
+    #[test]
+    fn rejects_loops_and_missing() {
+        let dev = FakeDev::new("loops");
+        assert_eq!(resolve(dev.fd, "/dev/loop"), None);
+        assert_eq!(resolve(dev.fd, "/dev/input/event1"), None);
+        assert_eq!(resolve(dev.fd, "/dev/"), None);
+    }
+}
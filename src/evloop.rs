@@ -0,0 +1,232 @@
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+use libc;
+use nix::unistd;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+pub enum Event {
+    /// A watched fd became readable
+    Readable(RawFd),
+    /// One of the watched signals was delivered
+    Signal(Signal),
+    /// The watched child process exited (raw wait status)
+    ProcExit(libc::c_int),
+    /// The time given to set_timeout has passed
+    Timeout,
+}
+
+/// Undoes watch_signals in a forked child before exec, which keeps the signal mask and ignored signals
+pub fn reset_signals(signals: &[Signal]) {
+    let mut mask = SigSet::empty();
+    for sig in signals {
+        mask.add(*sig);
+        let _ = unsafe { sigaction(*sig, &SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty())) };
+    }
+    let _ = mask.thread_unblock();
+}
+
+/// Time left until the deadline, None once it has passed
+fn remaining(deadline: Instant) -> Option<Duration> {
+    let now = Instant::now();
+    if now >= deadline {
+        None
+    } else {
+        Some(deadline - now)
+    }
+}
+
+#[cfg(target_os = "freebsd")]
+mod imp {
+    use std::os::unix::io::RawFd;
+    use libc;
+    use nix;
+    use nix::sys::event::*;
+    use nix::sys::signal::*;
+    use std::time::{Duration, Instant};
+    use pdfork::ChildHandle;
+    use super::{remaining, Event};
+
+    pub struct EventLoop {
+        pub kq: RawFd,
+        deadline: Option<Instant>,
+    }
+
+    impl EventLoop {
+        pub fn new() -> nix::Result<EventLoop> {
+            Ok(EventLoop { kq: kqueue()?, deadline: None })
+        }
+
+        /// Makes wait return Event::Timeout once, after the given time (replacing any earlier timeout)
+        pub fn set_timeout(&mut self, after: Duration) {
+            self.deadline = Some(Instant::now() + after);
+        }
+
+        fn add(&mut self, ident: usize, filter: EventFilter) -> nix::Result<()> {
+            let add = EventFlag::EV_ADD | EventFlag::EV_ENABLE;
+            kevent(self.kq, &vec![KEvent::new(ident, filter, add, FilterFlag::empty(), 0, 0)], &mut vec![], 0)?;
+            Ok(())
+        }
+
+        pub fn watch_read(&mut self, fd: RawFd) -> nix::Result<()> {
+            self.add(fd as usize, EventFilter::EVFILT_READ)
+        }
+
+        pub fn unwatch_read(&mut self, fd: RawFd) -> nix::Result<()> {
+            let delete = KEvent::new(fd as usize, EventFilter::EVFILT_READ, EventFlag::EV_DELETE, FilterFlag::empty(), 0, 0);
+            kevent(self.kq, &vec![delete], &mut vec![], 0)?;
+            Ok(())
+        }
+
+        pub fn watch_proc(&mut self, child: &ChildHandle) -> nix::Result<()> {
+            self.add(child.child_pd as usize, EventFilter::EVFILT_PROCDESC)
+        }
+
+        pub fn watch_signals(&mut self, signals: &[Signal]) -> nix::Result<()> {
+            for sig in signals {
+                self.add(*sig as usize, EventFilter::EVFILT_SIGNAL)?;
+                // kqueue sees signals even when they're ignored
+                unsafe { sigaction(*sig, &SigAction::new(SigHandler::SigIgn, SaFlags::empty(), SigSet::empty())) }?;
+            }
+            Ok(())
+        }
+
+        pub fn wait(&mut self) -> nix::Result<Event> {
+            loop {
+                let timeout = match self.deadline.map(remaining) {
+                    Some(Some(left)) => Some(libc::timespec { tv_sec: left.as_secs() as _, tv_nsec: left.subsec_nanos() as _ }),
+                    Some(None) => {
+                        self.deadline = None;
+                        return Ok(Event::Timeout);
+                    },
+                    None => None,
+                };
+                let mut eventlist = vec![KEvent::new(0, EventFilter::EVFILT_READ, EventFlag::empty(), FilterFlag::empty(), 0, 0)];
+                if kevent_ts(self.kq, &vec![], &mut eventlist, timeout)? == 0 {
+                    continue;
+                }
+                debug!("kevent: filter {:?} ident {:?}", eventlist[0].filter(), eventlist[0].ident());
+                match eventlist[0].filter() {
+                    EventFilter::EVFILT_READ => return Ok(Event::Readable(eventlist[0].ident() as RawFd)),
+                    EventFilter::EVFILT_SIGNAL => return Ok(Event::Signal(Signal::from_c_int(eventlist[0].ident() as libc::c_int)?)),
+                    EventFilter::EVFILT_PROCDESC => return Ok(Event::ProcExit(eventlist[0].data() as libc::c_int)),
+                    _ => {},
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::os::unix::io::{RawFd, AsRawFd};
+    use libc;
+    use nix;
+    use nix::sys::epoll::*;
+    use nix::sys::signal::{Signal, SigSet};
+    use nix::sys::signalfd::{SignalFd, SfdFlags};
+    use std::time::{Duration, Instant};
+    use pidfd::ChildHandle;
+    use super::{remaining, Event};
+
+    pub struct EventLoop {
+        pub ep: RawFd,
+        sigfd: Option<SignalFd>,
+        child: Option<(RawFd, libc::pid_t)>,
+        deadline: Option<Instant>,
+    }
+
+    impl EventLoop {
+        pub fn new() -> nix::Result<EventLoop> {
+            Ok(EventLoop {
+                ep: epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC)?,
+                sigfd: None,
+                child: None,
+                deadline: None,
+            })
+        }
+
+        /// Makes wait return Event::Timeout once, after the given time (replacing any earlier timeout)
+        pub fn set_timeout(&mut self, after: Duration) {
+            self.deadline = Some(Instant::now() + after);
+        }
+
+        pub fn watch_read(&mut self, fd: RawFd) -> nix::Result<()> {
+            epoll_ctl(self.ep, EpollOp::EpollCtlAdd, fd, &mut EpollEvent::new(EpollFlags::EPOLLIN, fd as u64))
+        }
+
+        pub fn unwatch_read(&mut self, fd: RawFd) -> nix::Result<()> {
+            epoll_ctl(self.ep, EpollOp::EpollCtlDel, fd, None::<&mut EpollEvent>)
+        }
+
+        /// Watches a pidfd, which becomes readable when the process exits
+        pub fn watch_proc(&mut self, child: &ChildHandle) -> nix::Result<()> {
+            self.child = Some((child.child_pd, child.child_pid));
+            self.watch_read(child.child_pd)
+        }
+
+        pub fn watch_signals(&mut self, signals: &[Signal]) -> nix::Result<()> {
+            let mut mask = SigSet::empty();
+            for sig in signals {
+                mask.add(*sig);
+            }
+            // signalfd only sees blocked signals (ignored ones are discarded)
+            mask.thread_block()?;
+            let sigfd = SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)?;
+            self.watch_read(sigfd.as_raw_fd())?;
+            self.sigfd = Some(sigfd);
+            Ok(())
+        }
+
+        pub fn wait(&mut self) -> nix::Result<Event> {
+            loop {
+                let timeout = match self.deadline.map(remaining) {
+                    // rounded up, so that it doesn't wake up just before the deadline
+                    Some(Some(left)) => (left.as_secs() * 1000 + (left.subsec_nanos() as u64).div_ceil(1_000_000)) as isize,
+                    Some(None) => {
+                        self.deadline = None;
+                        return Ok(Event::Timeout);
+                    },
+                    None => -1,
+                };
+                let mut events = [EpollEvent::empty()];
+                if epoll_wait(self.ep, &mut events, timeout)? == 0 {
+                    continue;
+                }
+                let fd = events[0].data() as RawFd;
+                debug!("epoll: fd {}", fd);
+                if let Some(ref mut sigfd) = self.sigfd {
+                    if fd == sigfd.as_raw_fd() {
+                        match sigfd.read_signal()? {
+                            Some(info) => return Ok(Event::Signal(Signal::from_c_int(info.ssi_signo as libc::c_int)?)),
+                            None => continue,
+                        }
+                    }
+                }
+                match self.child {
+                    Some((child_pd, child_pid)) if fd == child_pd => {
+                        let mut status = 0;
+                        if unsafe { libc::waitpid(child_pid, &mut status, libc::WNOHANG) } <= 0 {
+                            continue;
+                        }
+                        self.child = None;
+                        let _ = epoll_ctl(self.ep, EpollOp::EpollCtlDel, fd, None::<&mut EpollEvent>);
+                        return Ok(Event::ProcExit(status));
+                    },
+                    _ => return Ok(Event::Readable(fd)),
+                }
+            }
+        }
+    }
+}
+
+pub use self::imp::EventLoop;
+
+impl Drop for EventLoop {
+    fn drop(&mut self) {
+        #[cfg(target_os = "freebsd")]
+        let _ = unistd::close(self.kq);
+        #[cfg(target_os = "linux")]
+        let _ = unistd::close(self.ep);
+    }
+}
+
@@ -1,6 +1,12 @@
 extern crate libc;
+#[macro_use]
 extern crate nix;
 extern crate tiny_nix_ipc;
+#[macro_use]
+extern crate log;
 
 pub mod protocol;
 pub mod priority;
+pub mod client;
+pub mod capi;
+pub mod vt;
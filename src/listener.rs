@@ -0,0 +1,67 @@
+//! Listening sockets for the compatibility frontends (seatd, logind), passed to the child by path
+
+use std::ffi::CString;
+use std::os::unix::io::RawFd;
+use libc;
+use nix;
+use nix::errno::Errno;
+use nix::unistd;
+use nix::fcntl::{self, OFlag};
+use nix::sys::stat;
+use nix::sys::socket::*;
+
+/// A listening socket in a private directory under /tmp
+pub struct Listener {
+    pub fd: RawFd,
+    pub path: String,
+    sock_name: String,
+    tmp_dir: RawFd,
+    sock_dir: RawFd,
+    dir_name: String,
+}
+
+impl Listener {
+    /// Must be called before sandboxing (binds a path). The directory is only accessible by the user.
+    /// It stays owned by root until the socket is set up, so the user can't swap anything in it.
+    pub fn new(uid: libc::uid_t, gid: libc::gid_t, sock_name: &str) -> nix::Result<Listener> {
+        let template = CString::new("/tmp/loginw-XXXXXX").unwrap();
+        let raw = template.into_raw();
+        let res = unsafe { libc::mkdtemp(raw) };
+        let dir_path = unsafe { CString::from_raw(raw) }.into_string().unwrap();
+        if res.is_null() {
+            return Err(nix::Error::Sys(Errno::last()));
+        }
+        let dir_name = dir_path["/tmp/".len()..].to_owned();
+        let path = format!("{}/{}", dir_path, sock_name);
+        let tmp_dir = fcntl::open("/tmp", OFlag::O_DIRECTORY | OFlag::O_CLOEXEC, stat::Mode::empty())?;
+        let sock_dir = fcntl::open(&dir_path as &str, OFlag::O_DIRECTORY | OFlag::O_CLOEXEC | OFlag::O_NOFOLLOW, stat::Mode::empty())?;
+        let fd = socket(AddressFamily::Unix, SockType::Stream, SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK, None)?;
+        let listener = Listener { fd, path, sock_name: sock_name.to_owned(), tmp_dir, sock_dir, dir_name };
+        bind(fd, &SockAddr::Unix(UnixAddr::new(&listener.path as &str)?))?;
+        let c_sock_name = CString::new(sock_name).unwrap();
+        Errno::result(unsafe { libc::fchownat(sock_dir, c_sock_name.as_ptr(), uid, gid, libc::AT_SYMLINK_NOFOLLOW) })?;
+        Errno::result(unsafe { libc::fchown(sock_dir, uid, gid) })?;
+        listen(fd, 1)?;
+        info!("listening on {}", listener.path);
+        Ok(listener)
+    }
+
+    /// Returns a nonblocking connection fd
+    pub fn accept(&self) -> nix::Result<RawFd> {
+        accept4(self.fd, SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK)
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let _ = unistd::close(self.fd);
+        let sock_name = CString::new(self.sock_name.clone()).unwrap();
+        let dir_name = CString::new(self.dir_name.clone()).unwrap();
+        unsafe {
+            libc::unlinkat(self.sock_dir, sock_name.as_ptr(), 0);
+            libc::unlinkat(self.tmp_dir, dir_name.as_ptr(), libc::AT_REMOVEDIR);
+        }
+        let _ = unistd::close(self.sock_dir);
+        let _ = unistd::close(self.tmp_dir);
+    }
+}
@@ -0,0 +1,195 @@
+//! A tiny subset of the systemd-logind D-Bus API (org.freedesktop.login1), for compositors
+//! and libinput users that only know how to TakeDevice. Served on a private peer-to-peer socket,
+//! passed to the child as the system bus address, with a single session on a single seat.
+
+use std::os::unix::io::RawFd;
+use dbus::{self, Arg, Message};
+
+/// We answer as both the bus and logind
+pub const BUS_NAME: &str = "org.freedesktop.login1";
+pub const SOCKET_NAME: &str = "bus.sock";
+pub const SESSION_ID: &str = "loginw";
+pub const SEAT_ID: &str = "seat0";
+
+const MANAGER_PATH: &str = "/org/freedesktop/login1";
+const SESSION_PATH: &str = "/org/freedesktop/login1/session/loginw";
+const SEAT_PATH: &str = "/org/freedesktop/login1/seat/seat0";
+
+const DBUS_IFACE: &str = "org.freedesktop.DBus";
+const PEER_IFACE: &str = "org.freedesktop.DBus.Peer";
+const PROPERTIES_IFACE: &str = "org.freedesktop.DBus.Properties";
+const MANAGER_IFACE: &str = "org.freedesktop.login1.Manager";
+const SESSION_IFACE: &str = "org.freedesktop.login1.Session";
+const SEAT_IFACE: &str = "org.freedesktop.login1.Seat";
+
+pub const ERR_NO_SUCH_SESSION: &str = "org.freedesktop.login1.NoSuchSession";
+pub const ERR_NO_SUCH_SEAT: &str = "org.freedesktop.login1.NoSuchSeat";
+pub const ERR_NOT_IN_CONTROL: &str = "org.freedesktop.login1.NotInControl";
+pub const ERR_DEVICE_IS_TAKEN: &str = "org.freedesktop.login1.DeviceIsTaken";
+pub const ERR_DEVICE_NOT_TAKEN: &str = "org.freedesktop.login1.DeviceNotTaken";
+
+#[derive(Debug)]
+pub enum Request {
+    /// Bus methods (Hello, AddMatch…) and Peer.Ping, answered with a fixed reply
+    Bus(Vec<Arg>),
+    GetSession(String),
+    GetSeat(String),
+    TakeControl,
+    ReleaseControl,
+    TakeDevice(u32, u32),
+    ReleaseDevice(u32, u32),
+    PauseDeviceComplete(u32, u32),
+    /// Accepted and ignored (SetType, SetIdleHint…)
+    Ignored,
+    Activate,
+    SwitchTo(u32),
+    GetProperty(String, String),
+    GetAllProperties(String),
+    /// Unknown method or wrong arguments: error name, message
+    Invalid(&'static str, String),
+}
+
+/// What the properties are computed from
+pub struct SessionInfo {
+    pub active: bool,
+    pub vt_num: u32,
+}
+
+fn session_path(path: &str) -> bool {
+    path == SESSION_PATH || path == "/org/freedesktop/login1/session/auto" || path == "/org/freedesktop/login1/session/self"
+}
+
+fn seat_path(path: &str) -> bool {
+    path == SEAT_PATH || path == "/org/freedesktop/login1/seat/auto" || path == "/org/freedesktop/login1/seat/self"
+}
+
+fn two_u32(args: &[Arg]) -> Option<(u32, u32)> {
+    match args {
+        [a, b] => Some((a.as_u32()?, b.as_u32()?)),
+        _ => None,
+    }
+}
+
+fn two_str(args: &[Arg]) -> Option<(String, String)> {
+    match args {
+        [a, b] => Some((a.as_str()?.to_owned(), b.as_str()?.to_owned())),
+        _ => None,
+    }
+}
+
+fn one_str(args: &[Arg]) -> Option<String> {
+    match args {
+        [a] => a.as_str().map(|s| s.to_owned()),
+        _ => None,
+    }
+}
+
+/// Maps a method call to what it asks for
+pub fn parse(msg: &Message) -> Request {
+    let path = msg.path.as_ref().map(|s| s as &str).unwrap_or("");
+    let iface = msg.interface.as_ref().map(|s| s as &str).unwrap_or("");
+    let member = msg.member.as_ref().map(|s| s as &str).unwrap_or("");
+    let args = &msg.args[..];
+    let bad_args = || Request::Invalid(dbus::ERR_INVALID_ARGS, format!("Invalid arguments for {}", member));
+    match (iface, member) {
+        (DBUS_IFACE, "Hello") => Request::Bus(vec![Arg::Str(":1.1".to_owned())]),
+        (DBUS_IFACE, "AddMatch") | (DBUS_IFACE, "RemoveMatch") => Request::Bus(vec![]),
+        (DBUS_IFACE, "GetNameOwner") => Request::Bus(vec![Arg::Str(BUS_NAME.to_owned())]),
+        (DBUS_IFACE, "GetId") => Request::Bus(vec![Arg::Str("0".repeat(32))]),
+        (PEER_IFACE, "Ping") => Request::Bus(vec![]),
+        (MANAGER_IFACE, _) if path != MANAGER_PATH => Request::Invalid(dbus::ERR_UNKNOWN_OBJECT, path.to_owned()),
+        (MANAGER_IFACE, "GetSession") => one_str(args).map(Request::GetSession).unwrap_or_else(bad_args),
+        (MANAGER_IFACE, "GetSessionByPID") => Request::GetSession(SESSION_ID.to_owned()),
+        (MANAGER_IFACE, "GetSeat") => one_str(args).map(Request::GetSeat).unwrap_or_else(bad_args),
+        (SESSION_IFACE, _) if !session_path(path) => Request::Invalid(ERR_NO_SUCH_SESSION, path.to_owned()),
+        (SESSION_IFACE, "TakeControl") => Request::TakeControl,
+        (SESSION_IFACE, "ReleaseControl") => Request::ReleaseControl,
+        (SESSION_IFACE, "TakeDevice") => two_u32(args).map(|(ma, mi)| Request::TakeDevice(ma, mi)).unwrap_or_else(bad_args),
+        (SESSION_IFACE, "ReleaseDevice") => two_u32(args).map(|(ma, mi)| Request::ReleaseDevice(ma, mi)).unwrap_or_else(bad_args),
+        (SESSION_IFACE, "PauseDeviceComplete") => {
+            two_u32(args).map(|(ma, mi)| Request::PauseDeviceComplete(ma, mi)).unwrap_or_else(bad_args)
+        },
+        (SESSION_IFACE, "Activate") => Request::Activate,
+        (SESSION_IFACE, "SetType") | (SESSION_IFACE, "SetIdleHint") => Request::Ignored,
+        (SEAT_IFACE, _) if !seat_path(path) => Request::Invalid(ERR_NO_SUCH_SEAT, path.to_owned()),
+        (SEAT_IFACE, "SwitchTo") => match args {
+            [Arg::U32(n)] => Request::SwitchTo(*n),
+            _ => bad_args(),
+        },
+        (PROPERTIES_IFACE, "Get") => two_str(args).map(|(i, p)| Request::GetProperty(i, p)).unwrap_or_else(bad_args),
+        (PROPERTIES_IFACE, "GetAll") => one_str(args).map(Request::GetAllProperties).unwrap_or_else(bad_args),
+        _ => Request::Invalid(dbus::ERR_UNKNOWN_METHOD, format!("Unknown method {}.{}", iface, member)),
+    }
+}
+
+/// Whether a GetSession/GetSeat argument refers to our session/seat
+pub fn is_own_id(id: &str, own: &str) -> bool {
+    id == own || id == "auto" || id == "self" || id.is_empty()
+}
+
+pub fn session_object() -> Arg {
+    Arg::Path(SESSION_PATH.to_owned())
+}
+
+pub fn seat_object() -> Arg {
+    Arg::Path(SEAT_PATH.to_owned())
+}
+
+const SESSION_PROPERTIES: &[&str] = &["Id", "Active", "State", "VTNr", "Seat", "Type", "Class", "Remote"];
+const SEAT_PROPERTIES: &[&str] = &["Id", "ActiveSession", "CanGraphical", "CanTTY"];
+
+pub fn property(iface: &str, name: &str, info: &SessionInfo) -> Option<Arg> {
+    Some(match (iface, name) {
+        (SESSION_IFACE, "Id") => Arg::Str(SESSION_ID.to_owned()),
+        (SESSION_IFACE, "Active") => Arg::Bool(info.active),
+        (SESSION_IFACE, "State") => Arg::Str(if info.active { "active" } else { "online" }.to_owned()),
+        (SESSION_IFACE, "VTNr") => Arg::U32(info.vt_num),
+        (SESSION_IFACE, "Seat") => Arg::Struct(vec![Arg::Str(SEAT_ID.to_owned()), seat_object()]),
+        (SESSION_IFACE, "Type") => Arg::Str("wayland".to_owned()),
+        (SESSION_IFACE, "Class") => Arg::Str("user".to_owned()),
+        (SESSION_IFACE, "Remote") => Arg::Bool(false),
+        (SEAT_IFACE, "Id") => Arg::Str(SEAT_ID.to_owned()),
+        (SEAT_IFACE, "ActiveSession") => Arg::Struct(vec![Arg::Str(SESSION_ID.to_owned()), session_object()]),
+        (SEAT_IFACE, "CanGraphical") | (SEAT_IFACE, "CanTTY") => Arg::Bool(true),
+        _ => return None,
+    })
+}
+
+/// The a{sv} of the given properties
+fn property_dict(iface: &str, names: &[&str], info: &SessionInfo) -> Arg {
+    let entries = names.iter()
+        .filter_map(|name| property(iface, name, info).map(|value| (name, value)))
+        .map(|(name, value)| Arg::DictEntry(Box::new(Arg::Str(name.to_string())), Box::new(Arg::Variant(Box::new(value)))))
+        .collect();
+    Arg::Array("{sv}".to_owned(), entries)
+}
+
+pub fn all_properties(iface: &str, info: &SessionInfo) -> Arg {
+    let names = match iface {
+        SESSION_IFACE => SESSION_PROPERTIES,
+        SEAT_IFACE => SEAT_PROPERTIES,
+        _ => &[],
+    };
+    property_dict(iface, names, info)
+}
+
+/// Device access revoked: "pause" (wants PauseDeviceComplete), "force" (already done) or "gone"
+pub fn pause_device(major: u32, minor: u32, typ: &str) -> Message {
+    Message::signal(SESSION_PATH, SESSION_IFACE, "PauseDevice", vec![Arg::U32(major), Arg::U32(minor), Arg::Str(typ.to_owned())])
+}
+
+/// Device usable again, with a new fd for revoked input devices (or the same one for DRM)
+pub fn resume_device(major: u32, minor: u32, fd: RawFd) -> Message {
+    let mut msg = Message::signal(SESSION_PATH, SESSION_IFACE, "ResumeDevice", vec![Arg::U32(major), Arg::U32(minor), Arg::Fd(0)]);
+    msg.fds.push(fd);
+    msg
+}
+
+/// The session's Active/State properties changed
+pub fn active_changed(info: &SessionInfo) -> Message {
+    Message::signal(SESSION_PATH, PROPERTIES_IFACE, "PropertiesChanged", vec![
+        Arg::Str(SESSION_IFACE.to_owned()),
+        property_dict(SESSION_IFACE, &["Active", "State"], info),
+        Arg::Array("s".to_owned(), vec![]),
+    ])
+}
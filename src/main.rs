@@ -1,5 +1,6 @@
 extern crate libc;
 extern crate rusty_sandbox;
+#[cfg(target_os = "freebsd")]
 extern crate pdfork;
 extern crate tiny_nix_ipc;
 #[macro_use]
@@ -9,41 +10,232 @@ extern crate log;
 extern crate pretty_env_logger;
 
 mod protocol;
+// shared with the library, which exports all of it
+#[allow(dead_code)]
 mod priority;
 mod vt;
+mod evloop;
+mod power;
+mod listener;
+mod devices;
+mod seatd;
+mod dbus;
+mod logind;
+mod policy;
+mod sessions;
+mod cli;
+mod respawn;
+#[cfg(target_os = "linux")]
+mod pidfd;
 
-use std::{env, str};
-use std::io::Write;
+use std::{env, fmt, io, mem, process, str};
+use std::collections::{HashMap, HashSet};
 use std::ffi::{CStr, OsString};
 use std::process::Command;
 use std::os::unix::process::CommandExt;
-use std::os::unix::io::{RawFd, AsRawFd};
+use std::os::unix::io::{RawFd, AsRawFd, FromRawFd};
+use std::time::{Duration, Instant};
+#[cfg(target_os = "freebsd")]
 use pdfork::*;
+#[cfg(target_os = "linux")]
+use pidfd::*;
 use tiny_nix_ipc::Socket;
 use nix::unistd;
+use nix::errno::Errno;
 use nix::fcntl::{self, OFlag};
 use nix::sys::stat;
-use nix::sys::event::*;
 use nix::sys::signal::*;
+use devices::{Device, DeviceKind, DeviceTable, DrmNode, Frontend, Release};
+use policy::Policy;
+use evloop::{Event, EventLoop};
+use listener::Listener;
+use power::{PowerAction, PowerBackend};
 use protocol::*;
 
 ioctl_write_int!(eviocrevoke, 'E', 0x91);
+#[cfg(target_os = "linux")]
+ioctl_write_int!(hidiocrevoke, 'H', 0x0d);
+
+/// Cuts off every fd of the open device, fails if the kernel can't do that for this device
+fn revoke(kind: DeviceKind, fd: RawFd) -> nix::Result<()> {
+    match kind {
+        DeviceKind::Input => unsafe { eviocrevoke(fd, 0) }.map(drop),
+        #[cfg(target_os = "linux")]
+        DeviceKind::Hidraw => unsafe { hidiocrevoke(fd, 0) }.map(drop),
+        _ => Err(nix::Error::Sys(Errno::EOPNOTSUPP)),
+    }
+}
 
 enum OutData<'a> {
     Nothing,
     Str(&'a str),
     U64(u64),
+    Bool(bool),
+    Hello(LoginwHelloData),
+}
+
+/// Request types handled by Loginw::handle, advertised in LoginwHello
+const IMPLEMENTED_REQUESTS: &[LoginwRequestType] = &[
+    LoginwRequestType::LoginwOpenInput,
+    LoginwRequestType::LoginwOpenDrm,
+    LoginwRequestType::LoginwCloseDevice,
+    LoginwRequestType::LoginwOpenDevice,
+    LoginwRequestType::LoginwAcquireVt,
+    LoginwRequestType::LoginwSwitchVt,
+    LoginwRequestType::LoginwPowerOff,
+    LoginwRequestType::LoginwReboot,
+    LoginwRequestType::LoginwSuspend,
+    LoginwRequestType::LoginwHibernate,
+    LoginwRequestType::LoginwCanSuspend,
+    LoginwRequestType::LoginwCanHibernate,
+    LoginwRequestType::LoginwHello,
+];
+
+/// LoginwRequest as received, before checking that the type is a known one
+#[repr(C)]
+struct RawRequest {
+    typ: u16,
+    dat: LoginwData,
+}
+
+/// Why a request failed, reported to the client as a LoginwError
+#[derive(Debug)]
+enum ReqError {
+    Sys(nix::Error),
+    Io(io::Error),
+    BadData,
+    NotADevice(String),
+    NoSuchDevice(u64),
+    NoVt,
+    Inactive,
+    Denied(String),
+    Unavailable,
+    Unsupported(u16),
+}
+
+impl fmt::Display for ReqError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReqError::Sys(ref e) => write!(f, "{}", e),
+            ReqError::Io(ref e) => write!(f, "{}", e),
+            ReqError::BadData => write!(f, "Request data is not a valid string"),
+            ReqError::NotADevice(ref path) => write!(f, "Not an allowed device path: {}", path),
+            ReqError::NoSuchDevice(id) => write!(f, "No open device with id {}", id),
+            ReqError::NoVt => write!(f, "No VT acquired"),
+            ReqError::Inactive => write!(f, "The session is not active"),
+            ReqError::Denied(ref what) => write!(f, "Denied by policy: {}", what),
+            ReqError::Unavailable => write!(f, "Not available on this machine"),
+            ReqError::Unsupported(typ) => write!(f, "Unsupported request type {}", typ),
+        }
+    }
+}
+
+impl ReqError {
+    fn code(&self) -> (LoginwErrorCode, i32) {
+        match *self {
+            ReqError::Sys(nix::Error::Sys(errno)) => (LoginwErrorCode::LoginwErrErrno, errno as i32),
+            ReqError::Sys(_) => (LoginwErrorCode::LoginwErrBadData, 0),
+            ReqError::Io(ref e) => match e.raw_os_error() {
+                Some(errno) => (LoginwErrorCode::LoginwErrErrno, errno),
+                None => (LoginwErrorCode::LoginwErrFailed, 0),
+            },
+            ReqError::BadData | ReqError::NoSuchDevice(_) => (LoginwErrorCode::LoginwErrBadData, 0),
+            ReqError::NotADevice(_) => (LoginwErrorCode::LoginwErrNotADevice, 0),
+            ReqError::NoVt => (LoginwErrorCode::LoginwErrNoVt, 0),
+            ReqError::Inactive => (LoginwErrorCode::LoginwErrErrno, libc::EPERM),
+            ReqError::Denied(_) => (LoginwErrorCode::LoginwErrDenied, 0),
+            ReqError::Unavailable => (LoginwErrorCode::LoginwErrUnavailable, 0),
+            ReqError::Unsupported(_) => (LoginwErrorCode::LoginwErrUnsupported, 0),
+        }
+    }
+}
+
+impl ReqError {
+    /// D-Bus error name, errnos use the System.Error.* names that sd-bus maps back to errno
+    fn dbus_name(&self) -> String {
+        match self.code() {
+            (LoginwErrorCode::LoginwErrErrno, errno) => format!("System.Error.{:?}", Errno::from_i32(errno)),
+            (LoginwErrorCode::LoginwErrNotADevice, _) => "System.Error.ENODEV".to_owned(),
+            (LoginwErrorCode::LoginwErrDenied, _) => "System.Error.EPERM".to_owned(),
+            (LoginwErrorCode::LoginwErrBadData, _) => dbus::ERR_INVALID_ARGS.to_owned(),
+            (LoginwErrorCode::LoginwErrUnavailable, _) => dbus::ERR_NOT_SUPPORTED.to_owned(),
+            (LoginwErrorCode::LoginwErrUnsupported, _) => dbus::ERR_UNKNOWN_METHOD.to_owned(),
+            _ => dbus::ERR_FAILED.to_owned(),
+        }
+    }
+}
+
+impl From<nix::Error> for ReqError {
+    fn from(e: nix::Error) -> ReqError {
+        ReqError::Sys(e)
+    }
+}
+
+impl From<io::Error> for ReqError {
+    fn from(e: io::Error) -> ReqError {
+        ReqError::Io(e)
+    }
+}
+
+/// The child's exit code, or 128 + the signal that killed it (like shells report it)
+fn exit_code(status: libc::c_int) -> i32 {
+    if libc::WIFEXITED(status) {
+        libc::WEXITSTATUS(status)
+    } else if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        1
+    }
+}
+
+fn data_str(dat: &mut LoginwData) -> Result<&str, ReqError> {
+    bytes_str(unsafe { &mut dat.bytes })
+}
+
+fn bytes_str(bytes: &mut [u8]) -> Result<&str, ReqError> {
+    let last = bytes.len() - 1;
+    bytes[last] = 0; // ensure CStr doesn't overread
+    unsafe { CStr::from_ptr(&bytes[0] as *const u8 as *const _) }
+        .to_str()
+        .map_err(|_| ReqError::BadData)
 }
 
 struct Loginw {
-    kq: RawFd,
+    evloop: EventLoop,
     dev_dir: RawFd,
     child_proc: ChildHandle,
     sock: Socket,
     vt: Option<vt::Vt>,
-    input_devs: Vec<RawFd>,
-    drm_dev: Option<RawFd>,
+    devices: DeviceTable,
     is_active: bool,
+    power: Box<dyn PowerBackend>,
+    policy: Policy,
+    seatd_listener: Option<Listener>,
+    seatd_conn: Option<seatd::Connection>,
+    /// Devices opened by the libseat client, the device ids are used as seatd device ids
+    seatd_devices: HashSet<u64>,
+    logind_listener: Option<Listener>,
+    logind_conn: Option<dbus::Connection>,
+    /// (major, minor) -> device id
+    logind_devices: HashMap<(u32, u32), u64>,
+    logind_control: bool,
+    grace_period: Duration,
+    /// SIGTERM/SIGINT was forwarded to the child, the grace period is running
+    terminating: bool,
+    /// SIGKILL was sent after the grace period
+    killed: bool,
+    exit_code: i32,
+    started: Instant,
+    /// Supervisor mode: new sockets from the launcher (see respawn)
+    respawn_ctl: Option<Socket>,
+    /// Supervisor mode: DRM devices of a crashed instance by path, for the next one to pick up
+    kept_drm: HashMap<String, u64>,
+    /// The VT loginw was started from, which may be acquired even though it's in use
+    own_vt: Option<libc::c_int>,
+    /// The VT to acquire when none is requested (--vt), None for the first free one
+    default_vt: Option<libc::c_int>,
+    /// Where the VT is left on exit (--exit-vt)
+    exit_vt: vt::OnExit,
 }
 
 impl Drop for Loginw {
@@ -51,138 +243,731 @@ impl Drop for Loginw {
         // (child_proc gets auto dropped)
         // ^^^ do not allow child to hang around without us, as that causes endless
         // "broken pipe" console spam with libweston
-        if let Some(drm_dev) = self.drm_dev {
-            unsafe { drmDropMaster(drm_dev) };
-            let _ = unistd::close(drm_dev);
+        self.set_drm_master(false);
+        for (_, dev) in self.devices.iter() {
+            if let Some(fd) = dev.fd {
+                let _ = unistd::close(fd);
+            }
         }
-        let _ = unistd::close(self.kq);
         let _ = unistd::close(self.dev_dir);
     }
 }
 
 impl Loginw {
-    fn new(sock: Socket, child_proc: ChildHandle) -> Loginw {
+    fn new(
+        sock: Socket,
+        child_proc: ChildHandle,
+        power: Box<dyn PowerBackend>,
+        policy: Policy,
+        seatd_listener: Option<Listener>,
+        logind_listener: Option<Listener>,
+        grace_period: Duration,
+    ) -> Loginw {
         Loginw {
-            kq: kqueue().expect("kqueue"),
+            evloop: EventLoop::new().expect("EventLoop::new"),
             dev_dir: fcntl::open("/dev", OFlag::O_DIRECTORY | OFlag::O_CLOEXEC | OFlag::O_NONBLOCK, stat::Mode::empty())
                 .expect("open"),
             child_proc,
             sock,
             vt: None,
-            input_devs: Vec::new(),
-            drm_dev: None,
+            devices: DeviceTable::new(),
             is_active: false,
+            power,
+            policy,
+            seatd_listener,
+            seatd_conn: None,
+            seatd_devices: HashSet::new(),
+            logind_listener,
+            logind_conn: None,
+            logind_devices: HashMap::new(),
+            logind_control: false,
+            grace_period,
+            terminating: false,
+            killed: false,
+            exit_code: 0,
+            started: Instant::now(),
+            respawn_ctl: None,
+            kept_drm: HashMap::new(),
+            own_vt: None,
+            default_vt: None,
+            exit_vt: vt::OnExit::Original,
         }
     }
 
     fn send(&mut self, typ: LoginwResponseType, dat: OutData, fd: Option<RawFd>) {
-        let mut resp = LoginwResponse::new(typ);
+        self.send_resp(LoginwResponse::new(typ), dat, fd)
+    }
+
+    fn send_error(&mut self, e: &ReqError) {
+        let (err, sys_errno) = e.code();
+        self.send_resp(LoginwResponse::error(err, sys_errno), OutData::Str(&format!("{}", e)), None)
+    }
+
+    fn send_resp(&mut self, mut resp: LoginwResponse, dat: OutData, fd: Option<RawFd>) {
+        let typ = resp.typ;
         match dat {
             OutData::Nothing => debug!("Sending {:?} | no data | fd {:?}", typ, fd),
             OutData::Str(ref s) => {
                 debug!("Sending {:?} | string data '{}' | fd {:?}", typ, s, fd);
-                write!(unsafe { &mut resp.dat.bytes[..] }, "{}", s).expect("write!");
+                // leave room for the NUL terminator, cut on a char boundary
+                let mut len = s.len().min(unsafe { resp.dat.bytes }.len() - 1);
+                while !s.is_char_boundary(len) {
+                    len -= 1;
+                }
+                unsafe { resp.dat.bytes[..len].copy_from_slice(&s.as_bytes()[..len]) };
             },
             OutData::U64(n) => {
                 debug!("Sending {:?} | u64 data '{}' | fd {:?}", typ, n, fd);
                 resp.dat.u64 = n;
-            }
+            },
+            OutData::Bool(b) => {
+                debug!("Sending {:?} | boolean data '{}' | fd {:?}", typ, b, fd);
+                resp.dat.boolean = b;
+            },
+            OutData::Hello(h) => {
+                debug!("Sending {:?} | hello data version {} caps {:#x} | fd {:?}", typ, h.version, h.capabilities, fd);
+                resp.dat.hello = h;
+            },
         }
-        if let Some(fd) = fd {
-            self.sock.send_struct(&resp, Some(&[fd][..])).expect(".sendmsg");
+        let res = if let Some(fd) = fd {
+            self.sock.send_struct(&resp, Some(&[fd][..]))
         } else {
-            self.sock.send_struct(&resp, None).expect(".sendmsg");
+            self.sock.send_struct(&resp, None)
+        };
+        if let Err(e) = res {
+            error!("sendmsg: {}", e);
         }
     }
 
-    fn process(&mut self, mut req: LoginwRequest) {
-        let last = unsafe { req.dat.bytes }.len() - 1;
-        unsafe {
-            req.dat.bytes[last] = 0;
-        } // ensure CStr doesn't overread
-        let dat_str = unsafe { CStr::from_ptr(&req.dat.bytes[0] as *const u8 as *const _) }
-            .to_str()
-            .expect("to_str");
-        match req.typ {
+    fn process(&mut self, req: RawRequest) {
+        let res = match LoginwRequestType::from_u16(req.typ) {
+            Some(typ) => self.handle(typ, req.dat),
+            None => Err(ReqError::Unsupported(req.typ)),
+        };
+        if let Err(e) = res {
+            warn!("request failed: {}", e);
+            self.send_error(&e);
+        }
+    }
+
+    /// Must send exactly one response on success, and none on error (process sends the error)
+    fn handle(&mut self, typ: LoginwRequestType, mut dat: LoginwData) -> Result<(), ReqError> {
+        match typ {
             LoginwRequestType::LoginwOpenInput => {
-                info!("input device requested: {}", dat_str);
-                if !dat_str.starts_with("/dev/input") {
-                    self.send(LoginwResponseType::LoginwError, OutData::Str(&format!("Not an input device path: {}", dat_str)), None);
-                    return;
-                }
-                match fcntl::openat(
-                    self.dev_dir,
-                    &format!("input/{}", dat_str.split('/').next_back().unwrap_or(" ")) as &str,
-                    OFlag::O_RDWR | OFlag::O_CLOEXEC | OFlag::O_NONBLOCK,
-                    stat::Mode::empty(),
-                ) {
-                    Ok(rfd) => {
-                        self.input_devs.push(rfd);
-                        self.send(LoginwResponseType::LoginwPassedFd, OutData::Nothing, Some(rfd));
-                    },
-                    Err(e) => {
-                        self.send(LoginwResponseType::LoginwError, OutData::Str(&format!("{:?}", e)), None);
-                    },
-                }
+                let (id, rfd) = self.open_device(DeviceKind::Input, data_str(&mut dat)?, Frontend::Native)?;
+                self.send(LoginwResponseType::LoginwPassedFd, OutData::U64(id), Some(rfd));
             },
             LoginwRequestType::LoginwOpenDrm => {
-                info!("DRM device requested: {}", dat_str);
-                if self.drm_dev.is_some() {
-                    warn!("opening more than one DRM device");
-                }
-                if !dat_str.starts_with("/dev/dri") {
-                    self.send(LoginwResponseType::LoginwError, OutData::Str(&format!("Not a DRM device path: {}", dat_str)), None);
-                    return;
-                }
-                match fcntl::openat(
-                    self.dev_dir,
-                    &format!("dri/{}", dat_str.split('/').next_back().unwrap_or(" ")) as &str,
-                    OFlag::O_RDWR | OFlag::O_CLOEXEC | OFlag::O_NONBLOCK,
-                    stat::Mode::empty(),
-                ) {
-                    Ok(rfd) => {
-                        self.drm_dev = Some(rfd);
-                        self.send(LoginwResponseType::LoginwPassedFd, OutData::Nothing, Some(rfd));
-                    },
-                    Err(e) => {
-                        self.send(LoginwResponseType::LoginwError, OutData::Str(&format!("{:?}", e)), None);
-                    },
+                let (id, rfd) = self.open_device(DeviceKind::Drm, data_str(&mut dat)?, Frontend::Native)?;
+                self.send(LoginwResponseType::LoginwPassedFd, OutData::U64(id), Some(rfd));
+            },
+            LoginwRequestType::LoginwOpenDevice => {
+                let req = unsafe { &mut dat.open_device };
+                let kind = LoginwDeviceClass::from_u16(req.device_class).map(DeviceKind::from_class).ok_or(ReqError::BadData)?;
+                let (id, rfd) = self.open_device(kind, bytes_str(&mut req.path)?, Frontend::Native)?;
+                self.send(LoginwResponseType::LoginwPassedFd, OutData::U64(id), Some(rfd));
+            },
+            LoginwRequestType::LoginwCloseDevice => {
+                let id = unsafe { dat.u64 };
+                // seatd/logind devices are closed through their own frontend, which tracks their ids
+                match self.devices.get(id) {
+                    Some(dev) if dev.owner == Frontend::Native => (),
+                    _ => return Err(ReqError::NoSuchDevice(id)),
                 }
+                self.close_device(id)?;
+                self.send(LoginwResponseType::LoginwDone, OutData::Nothing, None);
             },
             LoginwRequestType::LoginwAcquireVt => {
-                if self.vt.is_none() {
-                    info!("VT requested, initializing");
-                    let tty_num = vt::find_free_tty(self.dev_dir).expect("find_free_tty");
-                    let tty_fd = vt::open_tty(self.dev_dir, tty_num).expect("open_tty");
-                    self.vt = Some(vt::Vt::new(tty_fd));
-                    self.is_active = true;
-                } else {
-                    info!("VT requested, resending");
-                }
-                if self.vt.is_some() {
-                    let (num, fd) = {
-                        let vt = self.vt.as_ref().unwrap();
-                        (OutData::U64(vt.vt_num as u64), vt.tty_fd)
-                    };
-                    self.send(LoginwResponseType::LoginwPassedFd, num, Some(fd));
-                } else {
-                    self.send(LoginwResponseType::LoginwError, OutData::Nothing, None);
+                let (num, fd) = self.acquire_vt(unsafe { dat.u64 })?;
+                self.send(LoginwResponseType::LoginwPassedFd, OutData::U64(num as u64), Some(fd));
+            },
+            LoginwRequestType::LoginwSwitchVt => {
+                self.switch_vt(unsafe { dat.u64 })?;
+                self.send(LoginwResponseType::LoginwDone, OutData::Nothing, None);
+            },
+            LoginwRequestType::LoginwPowerOff => self.power_action(PowerAction::PowerOff)?,
+            LoginwRequestType::LoginwReboot => self.power_action(PowerAction::Reboot)?,
+            LoginwRequestType::LoginwSuspend => self.power_action(PowerAction::Suspend)?,
+            LoginwRequestType::LoginwHibernate => self.power_action(PowerAction::Hibernate)?,
+            LoginwRequestType::LoginwCanSuspend => {
+                let can = self.power_allowed(PowerAction::Suspend).is_ok() && self.power.can(PowerAction::Suspend);
+                self.send(LoginwResponseType::LoginwDone, OutData::Bool(can), None);
+            },
+            LoginwRequestType::LoginwCanHibernate => {
+                let can = self.power_allowed(PowerAction::Hibernate).is_ok() && self.power.can(PowerAction::Hibernate);
+                self.send(LoginwResponseType::LoginwDone, OutData::Bool(can), None);
+            },
+            LoginwRequestType::LoginwHello => {
+                info!("client says hello, protocol version {}", unsafe { dat.hello.version });
+                let capabilities = IMPLEMENTED_REQUESTS.iter().fold(0, |caps, typ| caps | typ.capability());
+                let hello = LoginwHelloData { version: LOGINW_PROTOCOL_VERSION, capabilities };
+                self.send(LoginwResponseType::LoginwDone, OutData::Hello(hello), None);
+            },
+        }
+        Ok(())
+    }
+
+    // Operations shared by the native, seatd and logind protocols
+
+    /// Opens a device node by full path and starts tracking it, returns the device id and fd
+    fn open_device(&mut self, kind: DeviceKind, path: &str, owner: Frontend) -> Result<(u64, RawFd), ReqError> {
+        info!("{:?} device requested: {}", kind, path);
+        if owner == Frontend::Native && kind == DeviceKind::Drm {
+            if let Some(id) = self.kept_drm.remove(path) {
+                if let Some(fd) = self.devices.get(id).and_then(|dev| dev.fd) {
+                    debug!("handing over device {} kept from the previous instance", id);
+                    return Ok((id, fd));
                 }
+            }
+        }
+        // input of the session on screen is not for us
+        if kind.release() == Release::Revoke && !self.is_active {
+            return Err(ReqError::Inactive);
+        }
+        // it would stay usable after the VT is switched away
+        if kind == DeviceKind::Hidraw {
+            self.probe_revoke(kind, path)?;
+        }
+        let (rfd, rdev, drm_node) = self.open_node(kind, path)?;
+        let id = self.devices.insert(Device {
+            path: path.to_owned(),
+            major: devices::major(rdev),
+            minor: devices::minor(rdev),
+            kind,
+            drm_node,
+            owner,
+            fd: Some(rfd),
+        });
+        Ok((id, rfd))
+    }
+
+    /// Opens a node by full path (symlinks are resolved inside /dev), only if it's a device of that kind.
+    /// Also returns the DRM node type for DRM devices.
+    fn open_node(&self, kind: DeviceKind, path: &str) -> Result<(RawFd, libc::dev_t, Option<DrmNode>), ReqError> {
+        let (name, rdev) = devices::lookup(self.dev_dir, kind, path).ok_or_else(|| ReqError::NotADevice(path.to_owned()))?;
+        let drm_node = if kind == DeviceKind::Drm { Some(devices::drm_node(&name, rdev)) } else { None };
+        if !self.policy.device_allowed(kind, drm_node) {
+            return Err(ReqError::Denied(path.to_owned()));
+        }
+        let rfd = fcntl::openat(
+            self.dev_dir,
+            &name as &str,
+            OFlag::O_RDWR | OFlag::O_CLOEXEC | OFlag::O_NONBLOCK | OFlag::O_NOFOLLOW | OFlag::O_NOCTTY,
+            stat::Mode::empty(),
+        )?;
+        // the node could have been replaced between the lookup and the open
+        match stat::fstat(rfd) {
+            Ok(ref st) if st.st_rdev == rdev && st.st_mode & libc::S_IFMT == libc::S_IFCHR => Ok((rfd, rdev, drm_node)),
+            _ => {
+                let _ = unistd::close(rfd);
+                Err(ReqError::NotADevice(path.to_owned()))
             },
-            _ => warn!("not implemented: {:?}", req.typ),
         }
     }
 
+    /// Revokes a separate fd of the device, to find out if release_vt will be able to
+    fn probe_revoke(&self, kind: DeviceKind, path: &str) -> Result<(), ReqError> {
+        let (probe, _, _) = self.open_node(kind, path)?;
+        let res = revoke(kind, probe);
+        let _ = unistd::close(probe);
+        res.map_err(|e| {
+            info!("refusing {} that can't be revoked: {}", path, e);
+            ReqError::Sys(nix::Error::Sys(Errno::EOPNOTSUPP))
+        })
+    }
+
+    /// Opens a revoked device again, returns the new fd (or the current one if it wasn't revoked)
+    fn reopen_device(&mut self, id: u64) -> Result<RawFd, ReqError> {
+        let (kind, path, major, minor) = match self.devices.get(id) {
+            Some(&Device { fd: Some(fd), .. }) => return Ok(fd),
+            Some(dev) => (dev.kind, dev.path.clone(), dev.major, dev.minor),
+            None => return Err(ReqError::NoSuchDevice(id)),
+        };
+        debug!("reopening device {} ({})", id, path);
+        let (rfd, rdev, _) = self.open_node(kind, &path)?;
+        // e.g. unplugged and the name reused by another device
+        if devices::major(rdev) != major || devices::minor(rdev) != minor {
+            let _ = unistd::close(rfd);
+            return Err(ReqError::NotADevice(path));
+        }
+        if let Some(dev) = self.devices.get_mut(id) {
+            dev.fd = Some(rfd);
+        }
+        Ok(rfd)
+    }
+
+    /// Forgets a device and closes our copy of its fd
+    fn close_device(&mut self, id: u64) -> Result<(), ReqError> {
+        let dev = self.devices.remove(id).ok_or(ReqError::NoSuchDevice(id))?;
+        debug!("closing device {} ({}, {}:{})", id, dev.path, dev.major, dev.minor);
+        self.kept_drm.retain(|_, kept| *kept != id);
+        if let Some(fd) = dev.fd {
+            if dev.kind == DeviceKind::Drm && dev.drm_node != Some(DrmNode::Render) {
+                unsafe { drmDropMaster(fd) };
+            }
+            let _ = unistd::close(fd);
+        }
+        Ok(())
+    }
+
+    /// Sets or drops DRM master on every open primary DRM node, a failure only affects that device
+    fn set_drm_master(&self, master: bool) {
+        for (id, dev) in self.devices.iter() {
+            let fd = match dev.fd {
+                Some(fd) if dev.kind == DeviceKind::Drm && dev.drm_node != Some(DrmNode::Render) => fd,
+                _ => continue,
+            };
+            debug!("{} DRM master on device {} ({})", if master { "setting" } else { "dropping" }, id, dev.path);
+            let res = unsafe { if master { drmSetMaster(fd) } else { drmDropMaster(fd) } };
+            if res != 0 {
+                warn!("DRM master {} failed on device {} ({}): {}", if master { "set" } else { "drop" }, id, dev.path, Errno::last());
+            }
+        }
+    }
+
+    /// Returns the VT number and the tty fd. requested is a VT number, 0 for the default.
+    fn acquire_vt(&mut self, requested: u64) -> Result<(libc::c_int, RawFd), ReqError> {
+        if requested > libc::c_int::MAX as u64 {
+            return Err(ReqError::Sys(nix::Error::invalid_argument()));
+        }
+        if let Some(ref vt) = self.vt {
+            if requested != 0 && requested != vt.vt_num as u64 {
+                warn!("VT {} requested, but VT {} is already acquired", requested, vt.vt_num);
+                return Err(ReqError::Sys(nix::Error::Sys(Errno::EBUSY)));
+            }
+        }
+        if self.vt.is_none() {
+            info!("VT requested, initializing");
+            let wanted = if requested == 0 { self.default_vt } else { Some(requested as libc::c_int) };
+            let vt_num = match wanted {
+                Some(n) => {
+                    vt::check_available(self.dev_dir, n, self.own_vt)?;
+                    n
+                },
+                None => vt::find_free_vt(self.dev_dir)?,
+            };
+            let tty_fd = vt::open_vt(self.dev_dir, vt_num)?;
+            self.vt = Some(vt::Vt::new(tty_fd, self.exit_vt)?);
+            self.is_active = true;
+        } else {
+            info!("VT requested, resending");
+        }
+        let vt = self.vt.as_ref().ok_or(ReqError::NoVt)?;
+        Ok((vt.vt_num, vt.tty_fd))
+    }
+
+    fn switch_vt(&mut self, vt_num: u64) -> Result<(), ReqError> {
+        info!("VT switch requested: {}", vt_num);
+        self.vt.as_ref().ok_or(ReqError::NoVt)?.activate(vt_num)?;
+        Ok(())
+    }
+
+    fn power_action(&mut self, action: PowerAction) -> Result<(), ReqError> {
+        info!("power action requested: {:?}", action);
+        self.power_allowed(action)?;
+        if !self.power.can(action) {
+            return Err(ReqError::Unavailable);
+        }
+        self.power.perform(action)?;
+        self.send(LoginwResponseType::LoginwDone, OutData::Nothing, None);
+        Ok(())
+    }
+
+    fn power_allowed(&self, action: PowerAction) -> Result<(), ReqError> {
+        let active = self.is_active && self.vt.is_some();
+        self.policy.power_allowed(action, active).map_err(ReqError::Denied)
+    }
+
     fn on_sock_event(&mut self) -> bool {
-        match self.sock.recv_struct::<LoginwRequest, [RawFd; 0]>() {
+        match self.sock.recv_struct::<RawRequest, [RawFd; 0]>() {
             Ok((req, _)) => self.process(req),
             Err(tiny_nix_ipc::errors::Error(tiny_nix_ipc::errors::ErrorKind::WrongRecvLength, _)) => {
-                info!("child process died");
-                return false;
+                // Most likely exiting, wait a bit for the exit status (then kill it like after the grace period)
+                info!("child process closed the socket");
+                let _ = self.evloop.unwatch_read(self.sock.as_raw_fd());
+                // the launcher restarts it or exits
+                if !self.terminating && self.respawn_ctl.is_none() {
+                    self.terminating = true;
+                    self.evloop.set_timeout(Duration::from_secs(1));
+                }
+            },
+            Err(e) => error!("recvmsg: {}", e),
+        }
+        true
+    }
+
+    fn on_respawn_event(&mut self) {
+        let res = match self.respawn_ctl {
+            Some(ref mut ctl) => ctl.recv_struct::<respawn::Respawned, [RawFd; 1]>(),
+            None => return,
+        };
+        match res {
+            Ok((msg, Some([fd]))) => {
+                info!("command restarted (restart {})", msg.count);
+                let _ = self.evloop.unwatch_read(self.sock.as_raw_fd());
+                self.sock = unsafe { Socket::from_raw_fd(fd) };
+                if let Err(e) = self.evloop.watch_read(self.sock.as_raw_fd()) {
+                    error!("watch_read: {}", e);
+                }
+                self.forget_native_client();
+            },
+            Ok((_, None)) => warn!("launcher message without a socket"),
+            Err(tiny_nix_ipc::errors::Error(tiny_nix_ipc::errors::ErrorKind::WrongRecvLength, _)) => {
+                // the launcher is exiting, its exit status comes next
+                if let Some(ctl) = self.respawn_ctl.take() {
+                    let _ = self.evloop.unwatch_read(ctl.as_raw_fd());
+                }
+            },
+            Err(e) => error!("recvmsg: {}", e),
+        }
+    }
+
+    /// After a crash: closes the old instance's devices, except DRM ones, which keep master for the next instance
+    fn forget_native_client(&mut self) {
+        let ids: Vec<(u64, DeviceKind, String)> = self.devices.iter()
+            .filter(|&(_, dev)| dev.owner == Frontend::Native)
+            .map(|(id, dev)| (*id, dev.kind, dev.path.clone()))
+            .collect();
+        for (id, kind, path) in ids {
+            if kind == DeviceKind::Drm {
+                self.kept_drm.insert(path, id);
+            } else {
+                let _ = self.close_device(id);
+            }
+        }
+    }
+
+    fn on_seatd_accept(&mut self) {
+        let conn = match self.seatd_listener.as_ref().map(|l| l.accept()) {
+            Some(Ok(fd)) => seatd::Connection::new(fd),
+            Some(Err(e)) => {
+                warn!("seatd accept: {}", e);
+                return;
             },
-            Err(e) => panic!("recvmsg: {}", e),
+            None => return,
+        };
+        if self.seatd_conn.is_some() {
+            warn!("rejecting second libseat client");
+            return;
+        }
+        if let Err(e) = self.evloop.watch_read(conn.fd) {
+            warn!("seatd watch: {}", e);
+            return;
+        }
+        info!("libseat client connected");
+        self.seatd_conn = Some(conn);
+    }
+
+    fn on_seatd_event(&mut self) {
+        let alive = self.seatd_conn.as_mut().map(|c| c.fill()).unwrap_or(false);
+        while let Some(req) = self.seatd_conn.as_mut().and_then(|c| c.next_request()) {
+            if let Err(e) = self.seatd_process(req) {
+                warn!("seatd request failed: {}", e);
+                let errno = match e.code() {
+                    (LoginwErrorCode::LoginwErrErrno, errno) => errno,
+                    (LoginwErrorCode::LoginwErrNotADevice, _) => libc::ENODEV,
+                    (LoginwErrorCode::LoginwErrNoVt, _) => libc::ENOTTY,
+                    _ => libc::EINVAL,
+                };
+                self.seatd_send(|c| c.error(errno));
+            }
+        }
+        if !alive {
+            info!("libseat client disconnected");
+            self.seatd_close_seat();
+            self.seatd_conn = None;
+        }
+    }
+
+    fn seatd_send<F: FnOnce(&mut seatd::Connection)>(&mut self, f: F) {
+        if let Some(ref mut conn) = self.seatd_conn {
+            f(conn);
+        }
+    }
+
+    fn seatd_close_seat(&mut self) {
+        let ids: Vec<u64> = self.seatd_devices.drain().collect();
+        for id in ids {
+            let _ = self.close_device(id);
+        }
+    }
+
+    /// Sends at most one reply on success (some seatd requests have none), none on error
+    fn seatd_process(&mut self, req: seatd::Request) -> Result<(), ReqError> {
+        match req {
+            seatd::Request::OpenSeat => {
+                self.acquire_vt(0)?;
+                self.seatd_send(|c| c.seat_opened());
+            },
+            seatd::Request::CloseSeat => {
+                self.seatd_close_seat();
+                self.seatd_send(|c| c.seat_closed());
+            },
+            seatd::Request::OpenDevice(path) => {
+                let kind = devices::detect(self.dev_dir, &path).ok_or_else(|| ReqError::NotADevice(path.clone()))?;
+                let (id, fd) = self.open_device(kind, &path, Frontend::Seatd)?;
+                self.seatd_devices.insert(id);
+                self.seatd_send(|c| c.device_opened(id as i32, fd));
+            },
+            seatd::Request::CloseDevice(id) => {
+                if id < 1 || !self.seatd_devices.remove(&(id as u64)) {
+                    return Err(ReqError::Sys(nix::Error::Sys(Errno::ENOENT)));
+                }
+                self.close_device(id as u64)?;
+                self.seatd_send(|c| c.device_closed());
+            },
+            seatd::Request::DisableSeat => {
+                // We don't wait for this ack: the VT is released right after notifying
+                debug!("libseat client acknowledged disable");
+            },
+            seatd::Request::SwitchSession(num) => {
+                if num < 1 {
+                    return Err(ReqError::BadData);
+                }
+                self.switch_vt(num as u64)?;
+            },
+            seatd::Request::Ping => {
+                self.seatd_send(|c| c.pong());
+            },
+            seatd::Request::Invalid(opcode) => return Err(ReqError::Unsupported(opcode)),
+        }
+        Ok(())
+    }
+
+    fn on_logind_accept(&mut self) {
+        let conn = match self.logind_listener.as_ref().map(|l| l.accept()) {
+            Some(Ok(fd)) => dbus::Connection::new(fd, logind::BUS_NAME),
+            Some(Err(e)) => {
+                warn!("logind accept: {}", e);
+                return;
+            },
+            None => return,
+        };
+        if self.logind_conn.is_some() {
+            warn!("rejecting second D-Bus client");
+            return;
+        }
+        if let Err(e) = self.evloop.watch_read(conn.fd) {
+            warn!("logind watch: {}", e);
+            return;
+        }
+        info!("D-Bus client connected");
+        self.logind_conn = Some(conn);
+    }
+
+    fn on_logind_event(&mut self) {
+        let alive = self.logind_conn.as_mut().map(|c| c.fill()).unwrap_or(false);
+        let mut broken = false;
+        loop {
+            let msg = match self.logind_conn.as_mut().map(|c| c.next_message()) {
+                Some(Ok(Some(msg))) => msg,
+                Some(Err(e)) => {
+                    warn!("D-Bus client: {}", e);
+                    broken = true;
+                    break;
+                },
+                _ => break,
+            };
+            if msg.typ != dbus::METHOD_CALL {
+                continue;
+            }
+            let req = logind::parse(&msg);
+            debug!("logind request: {:?}", req);
+            let reply = self.logind_process(&msg, req).unwrap_or_else(|e| {
+                warn!("logind request failed: {}", e);
+                dbus::Message::error(&msg, &e.dbus_name(), &format!("{}", e))
+            });
+            if msg.expects_reply() {
+                self.logind_send(reply);
+            }
+        }
+        if !alive || broken {
+            info!("D-Bus client disconnected");
+            self.logind_release_control();
+            self.logind_conn = None;
+        }
+    }
+
+    fn logind_send(&mut self, msg: dbus::Message) {
+        if let Some(ref mut conn) = self.logind_conn {
+            conn.send(msg);
+        }
+    }
+
+    fn logind_info(&self) -> logind::SessionInfo {
+        logind::SessionInfo {
+            active: self.is_active,
+            vt_num: self.vt.as_ref().map(|vt| vt.vt_num as u32).unwrap_or(0),
+        }
+    }
+
+    fn logind_release_control(&mut self) {
+        self.logind_control = false;
+        let ids: Vec<u64> = self.logind_devices.drain().map(|(_, id)| id).collect();
+        for id in ids {
+            let _ = self.close_device(id);
+        }
+    }
+
+    /// Returns the reply (which can be a D-Bus error), errors are turned into D-Bus errors by the caller
+    fn logind_process(&mut self, msg: &dbus::Message, req: logind::Request) -> Result<dbus::Message, ReqError> {
+        let args = match req {
+            logind::Request::Bus(args) => args,
+            logind::Request::GetSession(ref id) if logind::is_own_id(id, logind::SESSION_ID) => vec![logind::session_object()],
+            logind::Request::GetSession(id) => return Ok(dbus::Message::error(msg, logind::ERR_NO_SUCH_SESSION, &id)),
+            logind::Request::GetSeat(ref id) if logind::is_own_id(id, logind::SEAT_ID) => vec![logind::seat_object()],
+            logind::Request::GetSeat(id) => return Ok(dbus::Message::error(msg, logind::ERR_NO_SUCH_SEAT, &id)),
+            logind::Request::TakeControl => {
+                self.acquire_vt(0)?;
+                self.logind_control = true;
+                vec![]
+            },
+            logind::Request::ReleaseControl => {
+                self.logind_release_control();
+                vec![]
+            },
+            logind::Request::TakeDevice(..) | logind::Request::ReleaseDevice(..) if !self.logind_control => {
+                return Ok(dbus::Message::error(msg, logind::ERR_NOT_IN_CONTROL, "Not in control"));
+            },
+            logind::Request::TakeDevice(major, minor) => {
+                if self.logind_devices.contains_key(&(major, minor)) {
+                    return Ok(dbus::Message::error(msg, logind::ERR_DEVICE_IS_TAKEN, "Device already taken"));
+                }
+                let path = devices::find_by_number(self.dev_dir, major, minor)?;
+                let kind = devices::detect(self.dev_dir, &path).ok_or_else(|| ReqError::NotADevice(path.clone()))?;
+                let (id, fd) = self.open_device(kind, &path, Frontend::Logind)?;
+                self.logind_devices.insert((major, minor), id);
+                let mut reply = dbus::Message::method_return(msg, vec![dbus::Arg::Fd(0), dbus::Arg::Bool(!self.is_active)]);
+                reply.fds.push(fd);
+                return Ok(reply);
+            },
+            logind::Request::ReleaseDevice(major, minor) => {
+                let id = match self.logind_devices.remove(&(major, minor)) {
+                    Some(id) => id,
+                    None => return Ok(dbus::Message::error(msg, logind::ERR_DEVICE_NOT_TAKEN, "Device not taken")),
+                };
+                self.close_device(id)?;
+                vec![]
+            },
+            logind::Request::PauseDeviceComplete(major, minor) => {
+                // We don't wait for this ack: devices are paused with "force"
+                debug!("D-Bus client acknowledged pause of {}:{}", major, minor);
+                vec![]
+            },
+            logind::Request::Ignored => vec![],
+            logind::Request::Activate => {
+                let vt_num = self.vt.as_ref().ok_or(ReqError::NoVt)?.vt_num;
+                self.switch_vt(vt_num as u64)?;
+                vec![]
+            },
+            logind::Request::SwitchTo(num) => {
+                self.switch_vt(num as u64)?;
+                vec![]
+            },
+            logind::Request::GetProperty(iface, name) => match logind::property(&iface, &name, &self.logind_info()) {
+                Some(value) => vec![dbus::Arg::Variant(Box::new(value))],
+                None => return Ok(dbus::Message::error(msg, dbus::ERR_UNKNOWN_PROPERTY, &name)),
+            },
+            logind::Request::GetAllProperties(iface) => vec![logind::all_properties(&iface, &self.logind_info())],
+            logind::Request::Invalid(name, text) => return Ok(dbus::Message::error(msg, name, &text)),
+        };
+        Ok(dbus::Message::method_return(msg, args))
+    }
+
+    /// Tells the D-Bus client that its devices were revoked and the session is inactive
+    fn logind_deactivated(&mut self) {
+        let devices: Vec<(u32, u32)> = self.logind_devices.keys().cloned().collect();
+        for (major, minor) in devices {
+            self.logind_send(logind::pause_device(major, minor, "force"));
+        }
+        let info = self.logind_info();
+        self.logind_send(logind::active_changed(&info));
+    }
+
+    /// Reopens the revoked input devices and hands them over, along with the DRM devices
+    fn logind_activated(&mut self) {
+        let devices: Vec<((u32, u32), u64)> = self.logind_devices.iter().map(|(k, v)| (*k, *v)).collect();
+        for ((major, minor), id) in devices {
+            match self.reopen_device(id) {
+                Ok(fd) => self.logind_send(logind::resume_device(major, minor, fd)),
+                Err(e) => {
+                    warn!("reopening device {}: {}", id, e);
+                    self.logind_devices.remove(&(major, minor));
+                    let _ = self.close_device(id);
+                    self.logind_send(logind::pause_device(major, minor, "gone"));
+                },
+            }
+        }
+        let info = self.logind_info();
+        self.logind_send(logind::active_changed(&info));
+    }
+
+    /// VT switched away: revoke what can be revoked, drop DRM master, notify, let the switch happen
+    fn release_vt(&mut self) {
+        self.is_active = false;
+        let mut paused = Vec::new();
+        for (id, dev) in self.devices.iter_mut() {
+            if dev.kind.release() != Release::Revoke {
+                continue;
+            }
+            let fd = match dev.fd {
+                Some(fd) => fd,
+                None => continue,
+            };
+            debug!("revoking {:?} device {} ({})", dev.kind, id, dev.path);
+            if let Err(e) = revoke(dev.kind, fd) {
+                // should not happen, open_device probed it: it stays usable, like the Keep classes
+                warn!("could not revoke device {} ({}): {}", id, dev.path, e);
+                continue;
+            }
+            let _ = unistd::close(fd);
+            dev.fd = None;
+            if dev.owner == Frontend::Native {
+                paused.push(*id);
+            }
+        }
+        for id in paused {
+            self.send(LoginwResponseType::LoginwDevicePaused, OutData::U64(id), None);
+        }
+        self.send(LoginwResponseType::LoginwDeactivated, OutData::Nothing, None);
+        self.seatd_send(|c| c.disable_seat());
+        self.logind_deactivated();
+        self.set_drm_master(false);
+        if let Some(ref vt) = self.vt {
+            vt.ack_release();
+        } else {
+            warn!("no VT");
+        }
+    }
+
+    /// VT switched back to us: the reverse of release_vt
+    fn acquire_vt_back(&mut self) {
+        if let Some(ref vt) = self.vt {
+            vt.ack_acquire();
+        } else {
+            warn!("no VT");
+        }
+        self.set_drm_master(true);
+        self.is_active = true;
+        self.resume_native_devices();
+        self.send(LoginwResponseType::LoginwActivated, OutData::Nothing, None);
+        self.seatd_send(|c| c.enable_seat());
+        self.logind_activated();
+    }
+
+    /// Reopens the devices revoked on VT release, and passes the new fds to the client
+    fn resume_native_devices(&mut self) {
+        let revoked: Vec<u64> = self.devices.iter()
+            .filter(|&(_, dev)| dev.owner == Frontend::Native && dev.fd.is_none())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in revoked {
+            match self.reopen_device(id) {
+                Ok(fd) => self.send(LoginwResponseType::LoginwDeviceResumed, OutData::U64(id), Some(fd)),
+                // It stays paused, most likely it was unplugged and the client will close it
+                Err(e) => warn!("reopening device {}: {}", id, e),
+            }
         }
-        return true;
     }
 
     fn on_signal_event(&mut self, signal: Signal) -> bool {
@@ -190,131 +975,254 @@ impl Loginw {
             Signal::SIGTERM | Signal::SIGINT => {
                 info!("received {:?}", signal);
                 let _ = self.child_proc.signal(signal as libc::c_int);
+                if !self.terminating {
+                    self.terminating = true;
+                    info!("giving the child process {:?} to exit", self.grace_period);
+                    self.evloop.set_timeout(self.grace_period);
+                }
             },
             Signal::SIGUSR1 => {
-                info!("received SIGUSR1 while is_active:{}", self.is_active);
+                info!("received VT release signal while is_active:{}", self.is_active);
+                self.release_vt();
+            },
+            Signal::SIGUSR2 => {
+                info!("received VT acquire signal while is_active:{}", self.is_active);
                 if self.is_active {
-                    if let Some(drm_dev) = self.drm_dev {
-                        self.is_active = false;
-                        for fd in self.input_devs.iter() {
-                            debug!("closing input device fd {}", fd);
-                            let _ = unsafe { eviocrevoke(*fd, 0) };
-                            let _ = unistd::close(*fd);
-                        }
-                        self.send(LoginwResponseType::LoginwDeactivated, OutData::Nothing, None);
-                        debug!("dropping DRM master");
-                        unsafe { drmDropMaster(drm_dev) };
-                        if let Some(ref vt) = self.vt {
-                            vt.ack_release();
-                        } else {
-                            warn!("no VT");
-                        }
-                    } else {
-                        warn!("no DRM device");
+                    // the switch to our VT in Vt::new, nothing was released yet
+                    if let Some(ref vt) = self.vt {
+                        vt.ack_acquire();
                     }
                 } else {
-                    if let Some(drm_dev) = self.drm_dev {
-                        if let Some(ref vt) = self.vt {
-                            vt.ack_acquire();
-                        } else {
-                            warn!("no VT");
-                        }
-                        debug!("setting DRM master");
-                        unsafe { drmSetMaster(drm_dev) };
-                        self.is_active = true;
-                        self.send(LoginwResponseType::LoginwActivated, OutData::Nothing, None);
-                    } else {
-                        warn!("no DRM device");
-                    }
+                    self.acquire_vt_back();
                 }
             },
-            s => warn!("unknown signal received from kqueue {:?}", s),
+            s => warn!("unknown signal received {:?}", s),
         }
-        return true;
+        true
     }
 
+    /// Exits with the child's exit code
     fn on_proc_event(&mut self, exit_status: libc::c_int) -> bool {
+        let code = exit_code(exit_status);
+        let signal = if libc::WIFSIGNALED(exit_status) { libc::WTERMSIG(exit_status) } else { 0 };
+        let runtime = self.started.elapsed();
         info!("child process exited with status {}", exit_status);
-        return false;
-    }
-
-    fn mainloop(&mut self) {
-        let add = EventFlag::EV_ADD | EventFlag::EV_ENABLE;
-        let filt = FilterFlag::empty();
-        kevent(
-            self.kq,
-            &vec![
-                KEvent::new(self.sock.as_raw_fd() as usize, EventFilter::EVFILT_READ, add, filt, 0, 0),
-                KEvent::new(self.child_proc.child_pd as usize, EventFilter::EVFILT_PROCDESC, add, filt, 0, 0),
-                KEvent::new(Signal::SIGINT as usize, EventFilter::EVFILT_SIGNAL, add, filt, 0, 0),
-                KEvent::new(Signal::SIGTERM as usize, EventFilter::EVFILT_SIGNAL, add, filt, 0, 0),
-                KEvent::new(Signal::SIGUSR1 as usize, EventFilter::EVFILT_SIGNAL, add, filt, 0, 0),
-            ],
-            &mut vec![],
-            0,
-        ).expect("kevent");
-        unsafe {
-            sigaction(Signal::SIGINT,  &SigAction::new(SigHandler::SigIgn, SaFlags::empty(), SigSet::empty())).unwrap();
-            sigaction(Signal::SIGTERM, &SigAction::new(SigHandler::SigIgn, SaFlags::empty(), SigSet::empty())).unwrap();
-            sigaction(Signal::SIGUSR1, &SigAction::new(SigHandler::SigIgn, SaFlags::empty(), SigSet::empty())).unwrap();
+        info!(target: "loginw::exit", "status={} signal={} core_dumped={} runtime={}.{:03}s",
+              code, signal, libc::WIFSIGNALED(exit_status) && libc::WCOREDUMP(exit_status),
+              runtime.as_secs(), runtime.subsec_millis());
+        self.exit_code = code;
+        false
+    }
+
+    /// Grace period over: kill the child, and give up on it if even that doesn't work in a second
+    fn on_timeout(&mut self) -> bool {
+        self.exit_code = 128 + libc::SIGKILL;
+        if self.killed {
+            warn!("child process still running after SIGKILL, exiting anyway");
+            return false;
         }
+        warn!("child process did not exit within {:?}, sending SIGKILL", self.grace_period);
+        self.killed = true;
+        let _ = self.child_proc.signal(libc::SIGKILL);
+        self.evloop.set_timeout(Duration::from_secs(1));
+        true
+    }
+
+    /// Returns the exit code for loginw
+    fn mainloop(&mut self) -> i32 {
+        self.evloop.watch_read(self.sock.as_raw_fd()).expect("watch_read");
+        if let Some(ref ctl) = self.respawn_ctl {
+            self.evloop.watch_read(ctl.as_raw_fd()).expect("watch_read");
+        }
+        if let Some(ref listener) = self.seatd_listener {
+            self.evloop.watch_read(listener.fd).expect("watch_read");
+        }
+        if let Some(ref listener) = self.logind_listener {
+            self.evloop.watch_read(listener.fd).expect("watch_read");
+        }
+        self.evloop.watch_proc(&self.child_proc).expect("watch_proc");
+        self.evloop.watch_signals(&[Signal::SIGINT, Signal::SIGTERM, Signal::SIGUSR1, Signal::SIGUSR2]).expect("watch_signals");
         loop {
-            let mut eventlist = vec![KEvent::new(0, EventFilter::EVFILT_READ, EventFlag::empty(), FilterFlag::empty(), 0, 0)];
-            kevent_ts(self.kq, &vec![], &mut eventlist, None).expect("kevent");
-            debug!("kevent: filter {:?} ident {:?}", eventlist[0].filter(), eventlist[0].ident());
-            match eventlist[0].filter() {
-                EventFilter::EVFILT_READ => if !self.on_sock_event() {
-                    break;
+            let cont = match self.evloop.wait().expect("wait") {
+                Event::Readable(fd) if fd == self.sock.as_raw_fd() => self.on_sock_event(),
+                Event::Readable(fd) if self.respawn_ctl.as_ref().map(|c| c.as_raw_fd()) == Some(fd) => {
+                    self.on_respawn_event();
+                    true
                 },
-                EventFilter::EVFILT_SIGNAL => if !self.on_signal_event(
-                    Signal::from_c_int(eventlist[0].ident() as libc::c_int).expect("signal from_c_int"),
-                ) {
-                    break;
+                Event::Readable(fd) if self.seatd_listener.as_ref().map(|l| l.fd) == Some(fd) => {
+                    self.on_seatd_accept();
+                    true
                 },
-                EventFilter::EVFILT_PROCDESC => if !self.on_proc_event(eventlist[0].data() as libc::c_int) {
-                    break;
+                Event::Readable(fd) if self.seatd_conn.as_ref().map(|c| c.fd) == Some(fd) => {
+                    self.on_seatd_event();
+                    true
                 },
-                _ => {},
+                Event::Readable(fd) if self.logind_listener.as_ref().map(|l| l.fd) == Some(fd) => {
+                    self.on_logind_accept();
+                    true
+                },
+                Event::Readable(fd) if self.logind_conn.as_ref().map(|c| c.fd) == Some(fd) => {
+                    self.on_logind_event();
+                    true
+                },
+                Event::Readable(fd) => {
+                    warn!("event for unknown fd {}", fd);
+                    true
+                },
+                Event::Signal(signal) => self.on_signal_event(signal),
+                Event::ProcExit(exit_status) => self.on_proc_event(exit_status),
+                Event::Timeout => self.on_timeout(),
+            };
+            if !cont {
+                break;
             }
         }
+        self.exit_code
     }
 }
 
 fn main() {
     pretty_env_logger::init();
+    vt::install_panic_hook();
     let args: Vec<OsString> = env::args_os().collect();
-    if args.len() < 2 {
-        panic!("No args");
+    let (opts, command) = match cli::parse(&args) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("loginw: {}\n{}", e, cli::USAGE);
+            process::exit(2);
+        },
+    };
+    let user_info = unsafe { &*libc::getpwuid(libc::getuid()) };
+    let mut policy = match Policy::load(policy::CONFIG_PATH) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("loginw: {}", e);
+            process::exit(1);
+        },
+    };
+    let user_name = policy::user_name(user_info);
+    if !policy.user_allowed(&user_name, user_info.pw_gid) {
+        eprintln!("loginw: user {} is not allowed to run loginw by {}", user_name, policy::CONFIG_PATH);
+        process::exit(1);
     }
+    policy.prepare(&user_name, user_info.pw_gid);
+    let own_vt = vt::controlling_vt();
+    let default_vt = match opts.vt {
+        Some(cli::VtChoice::Number(n)) => Some(n),
+        Some(cli::VtChoice::Current) => match own_vt {
+            Some(n) => Some(n),
+            None => {
+                eprintln!("loginw: --vt=current, but not started from a VT");
+                process::exit(1);
+            },
+        },
+        None => None,
+    };
+    let power = power::backend_from_env();
+    let seatd_listener = if opts.seatd {
+        Some(Listener::new(user_info.pw_uid, user_info.pw_gid, seatd::SOCKET_NAME).expect("Listener::new"))
+    } else {
+        None
+    };
+    let logind_listener = if opts.logind {
+        Some(Listener::new(user_info.pw_uid, user_info.pw_gid, logind::SOCKET_NAME).expect("Listener::new"))
+    } else {
+        None
+    };
     let (sock_parent, mut sock_child) = Socket::new_socketpair().expect("socketpair");
     sock_child.no_cloexec().unwrap();
+    let respawn_ctl = if opts.respawn.is_some() {
+        Some(Socket::new_socketpair().expect("socketpair"))
+    } else {
+        None
+    };
     match fork() {
         ForkResult::Fail => panic!("fork"),
         ForkResult::Parent(child_proc) => {
             drop(sock_child);
-            let mut server = Loginw::new(sock_parent, child_proc);
+            let mut server = Loginw::new(sock_parent, child_proc, power, policy, seatd_listener, logind_listener, opts.grace_period);
+            server.respawn_ctl = respawn_ctl.map(|(ctl, _)| ctl);
+            server.own_vt = own_vt;
+            server.default_vt = default_vt;
+            server.exit_vt = opts.exit_vt;
             rusty_sandbox::Sandbox::new().sandbox_this_process();
-            server.mainloop();
+            let code = server.mainloop();
+            // restores the VT, process::exit doesn't run destructors
+            drop(server);
+            process::exit(code);
         },
         ForkResult::Child => {
             drop(sock_parent);
-            if !priority::make_realtime() {
-                warn!("Could not set realtime priority");
-            }
-            let user_info = unsafe { &*libc::getpwuid(libc::getuid()) };
-            if unsafe { libc::initgroups(user_info.pw_name, user_info.pw_gid) } != 0 {
-                panic!("initgroups");
+            // The socket files belong to the parent, don't let Drop remove them
+            let seatd_sock = seatd_listener.as_ref().map(|l| l.path.clone());
+            mem::forget(seatd_listener);
+            let logind_sock = logind_listener.as_ref().map(|l| l.path.clone());
+            mem::forget(logind_listener);
+            let exec = |sock_fd: RawFd| exec_command(command, user_info, sock_fd, &seatd_sock, &logind_sock);
+            match (opts.respawn, respawn_ctl) {
+                (Some(limits), Some((loginw_end, ctl))) => {
+                    // loginw sees the launcher exit by its end being closed
+                    drop(loginw_end);
+                    respawn::run(ctl, sock_child, limits, exec)
+                },
+                _ => {
+                    exec(sock_child.as_raw_fd());
+                    process::exit(127);
+                },
             }
-            Command::new(&args[1])
-                .args(&args[2..])
-                .uid(user_info.pw_uid)
-                .gid(user_info.pw_gid)
-                .env("LOGINW_FD", format!("{}", sock_child.as_raw_fd()))
-                .exec();
         }
     }
 }
 
+/// Drops privileges and runs the command with the given loginw socket, only returns on failure
+fn exec_command(
+    command: &[OsString],
+    user_info: &libc::passwd,
+    sock_fd: RawFd,
+    seatd_sock: &Option<String>,
+    logind_sock: &Option<String>,
+) {
+    if !priority::make_realtime() {
+        warn!("Could not set realtime priority");
+    }
+    if unsafe { libc::initgroups(user_info.pw_name, user_info.pw_gid) } != 0 {
+        panic!("initgroups");
+    }
+    let mut cmd = Command::new(&command[0]);
+    cmd.args(&command[1..])
+        .uid(user_info.pw_uid)
+        .gid(user_info.pw_gid)
+        .env("LOGINW_FD", format!("{}", sock_fd));
+    if let Some(ref path) = *seatd_sock {
+        cmd.env("SEATD_SOCK", path).env("LIBSEAT_BACKEND", "seatd");
+    }
+    if let Some(ref path) = *logind_sock {
+        cmd.env("DBUS_SYSTEM_BUS_ADDRESS", format!("unix:path={}", path))
+            .env("XDG_SESSION_ID", logind::SESSION_ID)
+            .env("XDG_SEAT", logind::SEAT_ID);
+    }
+    // Don't outlive loginw or the launcher (pdfork children are killed when the descriptor is closed).
+    // Only after the credential change, which clears it
+    #[cfg(target_os = "linux")]
+    {
+        let parent = unistd::getppid();
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // it might have died before that
+                if unistd::getppid() != parent {
+                    return Err(io::Error::from_raw_os_error(libc::ESRCH));
+                }
+                Ok(())
+            });
+        }
+    }
+    let e = cmd.exec();
+    error!("could not run {:?}: {}", command[0], e);
+}
+
 #[link(name = "drm")]
 extern "C" {
     fn drmSetMaster(fd: RawFd) -> libc::c_int;
@@ -0,0 +1,54 @@
+//! A pdfork-like API on top of Linux pidfds
+
+use std::ptr;
+use std::os::unix::io::RawFd;
+use libc;
+use nix::{self, unistd};
+use nix::errno::Errno;
+
+// Not in libc yet. Numbers from the unified syscall table, which every architecture
+// Rust targets uses for syscalls added since 5.1 (only alpha has its own)
+const SYS_PIDFD_SEND_SIGNAL: libc::c_long = 424;
+const SYS_PIDFD_OPEN: libc::c_long = 434;
+
+pub struct ChildHandle {
+    pub child_pd: RawFd,
+    pub child_pid: libc::pid_t,
+}
+
+impl ChildHandle {
+    pub fn signal(&self, sig: libc::c_int) -> nix::Result<()> {
+        let res = unsafe { libc::syscall(SYS_PIDFD_SEND_SIGNAL, self.child_pd, sig, ptr::null::<libc::siginfo_t>(), 0) };
+        Errno::result(res).map(drop)
+    }
+}
+
+impl Drop for ChildHandle {
+    fn drop(&mut self) {
+        // Same semantics as closing a process descriptor on FreeBSD
+        let _ = self.signal(libc::SIGKILL);
+        let _ = unistd::close(self.child_pd);
+    }
+}
+
+pub enum ForkResult {
+    Fail,
+    Parent(ChildHandle),
+    Child,
+}
+
+pub fn fork() -> ForkResult {
+    match unsafe { libc::fork() } {
+        -1 => ForkResult::Fail,
+        0 => ForkResult::Child,
+        child_pid => {
+            // pidfds are always close-on-exec
+            let child_pd = unsafe { libc::syscall(SYS_PIDFD_OPEN, child_pid, 0) };
+            if child_pd < 0 {
+                unsafe { libc::kill(child_pid, libc::SIGKILL) };
+                return ForkResult::Fail;
+            }
+            ForkResult::Parent(ChildHandle { child_pd: child_pd as RawFd, child_pid })
+        },
+    }
+}
@@ -0,0 +1,351 @@
+//! Access policy, read from a config file that only root can change.
+//! Without the file, everything is allowed (the behaviour before the policy existed),
+//! except the device classes other than input and drm, which have to be listed explicitly.
+//!
+//! The format is `key = value, value…` lines, with `#` comments:
+//!
+//! ```text
+//! # Device classes that may be opened: input, drm, sound, video, hidraw, fb
+//! devices = input, drm
+//! # DRM node types that may be opened
+//! drm_nodes = primary, render
+//! # Who may run loginw: listed users and members of listed groups (anyone if neither is set)
+//! users = alice
+//! groups = video
+//! # Power actions: yes, no, or conditions that must all hold:
+//! #   group:NAME  member of NAME (several group: conditions mean any of them)
+//! #   active      the session is on the active VT
+//! #   alone       no other users are logged in
+//! # power sets the default for the four actions
+//! power = yes
+//! power_off = group:operator, active, alone
+//! reboot = group:operator, active, alone
+//! suspend = active
+//! hibernate = active
+//! ```
+
+use std::{fs, io};
+use std::ffi::{CStr, CString};
+use libc;
+use devices::{DeviceKind, DrmNode};
+use power::PowerAction;
+use sessions::Sessions;
+
+/// Not overridable: loginw is setuid, the user must not be able to pick the policy
+#[cfg(target_os = "freebsd")]
+pub const CONFIG_PATH: &str = "/usr/local/etc/loginw.conf";
+#[cfg(target_os = "linux")]
+pub const CONFIG_PATH: &str = "/etc/loginw.conf";
+
+pub struct Policy {
+    pub device_kinds: Vec<DeviceKind>,
+    pub drm_nodes: Vec<DrmNode>,
+    pub users: Option<Vec<String>>,
+    pub groups: Option<Vec<String>>,
+    pub power: PowerRule,
+    /// Indexed by PowerAction, None means the power rule applies
+    pub power_actions: [Option<PowerRule>; 4],
+    /// Set up by prepare
+    user_name: String,
+    sessions: Option<Sessions>,
+}
+
+/// Who may perform a power action
+#[derive(Debug, Clone)]
+pub struct PowerRule {
+    pub allowed: bool,
+    /// Any of these groups, everyone if empty
+    pub groups: Vec<String>,
+    pub require_active: bool,
+    pub require_alone: bool,
+    /// Group membership, resolved by prepare (the group database is not readable in the sandbox)
+    in_group: bool,
+}
+
+impl PowerRule {
+    fn new(allowed: bool) -> PowerRule {
+        PowerRule { allowed, groups: Vec::new(), require_active: false, require_alone: false, in_group: true }
+    }
+
+    fn parse(value: &str) -> Result<PowerRule, String> {
+        if let Ok(allowed) = parse_bool(value) {
+            return Ok(PowerRule::new(allowed));
+        }
+        let mut rule = PowerRule::new(true);
+        for cond in value.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()) {
+            match cond {
+                "active" => rule.require_active = true,
+                "alone" => rule.require_alone = true,
+                _ if cond.starts_with("group:") => rule.groups.push(cond["group:".len()..].to_owned()),
+                _ => return Err(format!("unknown condition '{}'", cond)),
+            }
+        }
+        Ok(rule)
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            device_kinds: vec![DeviceKind::Input, DeviceKind::Drm],
+            drm_nodes: vec![DrmNode::Primary, DrmNode::Render],
+            users: None,
+            groups: None,
+            power: PowerRule::new(true),
+            power_actions: [None, None, None, None],
+            user_name: String::new(),
+            sessions: None,
+        }
+    }
+}
+
+fn parse_list<T, F: Fn(&str) -> Option<T>>(value: &str, f: F) -> Result<Vec<T>, String> {
+    value.split(',')
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|v| f(v).ok_or_else(|| format!("unknown value '{}'", v)))
+        .collect()
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "yes" | "true" | "1" => Ok(true),
+        "no" | "false" | "0" => Ok(false),
+        _ => Err(format!("expected yes or no, got '{}'", value)),
+    }
+}
+
+impl Policy {
+    /// A missing file means the default policy, a broken one is an error
+    pub fn load(path: &str) -> Result<Policy, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => Policy::parse(&text).map_err(|e| format!("{}: {}", path, e)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!("no policy file at {}, allowing everything", path);
+                Ok(Policy::default())
+            },
+            Err(e) => Err(format!("{}: {}", path, e)),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Policy, String> {
+        let mut policy = Policy::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut kv = line.splitn(2, '=');
+            let key = kv.next().unwrap_or("").trim();
+            let value = kv.next().ok_or_else(|| format!("line {}: expected key = value", i + 1))?.trim();
+            let res = match key {
+                "devices" => parse_list(value, DeviceKind::from_name).map(|v| policy.device_kinds = v),
+                "drm_nodes" => parse_list(value, DrmNode::from_name).map(|v| policy.drm_nodes = v),
+                "users" => parse_list(value, |v| Some(v.to_owned())).map(|v| policy.users = Some(v)),
+                "groups" => parse_list(value, |v| Some(v.to_owned())).map(|v| policy.groups = Some(v)),
+                "power" => PowerRule::parse(value).map(|v| policy.power = v),
+                "power_off" => PowerRule::parse(value).map(|v| policy.power_actions[PowerAction::PowerOff as usize] = Some(v)),
+                "reboot" => PowerRule::parse(value).map(|v| policy.power_actions[PowerAction::Reboot as usize] = Some(v)),
+                "suspend" => PowerRule::parse(value).map(|v| policy.power_actions[PowerAction::Suspend as usize] = Some(v)),
+                "hibernate" => PowerRule::parse(value).map(|v| policy.power_actions[PowerAction::Hibernate as usize] = Some(v)),
+                _ => Err(format!("unknown key '{}'", key)),
+            };
+            res.map_err(|e| format!("line {}: {}", i + 1, e))?;
+        }
+        Ok(policy)
+    }
+
+    /// Whether the user may run loginw at all
+    pub fn user_allowed(&self, name: &str, gid: libc::gid_t) -> bool {
+        if self.users.is_none() && self.groups.is_none() {
+            return true;
+        }
+        if let Some(ref users) = self.users {
+            if users.iter().any(|u| u == name) {
+                return true;
+            }
+        }
+        if let Some(ref groups) = self.groups {
+            let member_of = user_groups(name, gid);
+            if groups.iter().filter_map(|g| group_id(g)).any(|g| member_of.contains(&g)) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Resolves what the power rules need from outside, must be called before sandboxing
+    pub fn prepare(&mut self, name: &str, gid: libc::gid_t) {
+        let member_of = user_groups(name, gid);
+        let rules = Some(&mut self.power).into_iter().chain(self.power_actions.iter_mut().filter_map(|r| r.as_mut()));
+        for rule in rules {
+            rule.in_group = rule.groups.is_empty() ||
+                rule.groups.iter().filter_map(|g| group_id(g)).any(|g| member_of.contains(&g));
+        }
+        self.user_name = name.to_owned();
+        self.sessions = Sessions::open();
+    }
+
+    fn power_rule(&self, action: PowerAction) -> &PowerRule {
+        self.power_actions[action as usize].as_ref().unwrap_or(&self.power)
+    }
+
+    /// Ok, or why the action is denied. active is whether the session is on the active VT.
+    pub fn power_allowed(&self, action: PowerAction, active: bool) -> Result<(), String> {
+        let rule = self.power_rule(action);
+        if !rule.allowed {
+            return Err(format!("{:?} is disabled", action));
+        }
+        if !rule.in_group {
+            return Err(format!("{:?} requires membership in {}", action, rule.groups.join(" or ")));
+        }
+        if rule.require_active && !active {
+            return Err(format!("{:?} requires the session to be on the active VT", action));
+        }
+        if rule.require_alone {
+            // an unreadable database counts as not alone
+            let others = self.sessions.as_ref().and_then(|s| s.others(&self.user_name));
+            if others != Some(0) {
+                return Err(format!("{:?} requires no other users to be logged in", action));
+            }
+        }
+        Ok(())
+    }
+
+    /// drm_node is only relevant for DRM devices
+    pub fn device_allowed(&self, kind: DeviceKind, drm_node: Option<DrmNode>) -> bool {
+        self.device_kinds.contains(&kind) && drm_node.map(|n| self.drm_nodes.contains(&n)).unwrap_or(true)
+    }
+}
+
+fn group_id(name: &str) -> Option<libc::gid_t> {
+    let name = CString::new(name).ok()?;
+    let gr = unsafe { libc::getgrnam(name.as_ptr()) };
+    if gr.is_null() {
+        warn!("unknown group {:?} in policy", name);
+        return None;
+    }
+    Some(unsafe { (*gr).gr_gid })
+}
+
+/// Primary and supplementary groups
+fn user_groups(name: &str, gid: libc::gid_t) -> Vec<libc::gid_t> {
+    let cname = match CString::new(name) {
+        Ok(n) => n,
+        Err(_) => return vec![gid],
+    };
+    let mut groups: Vec<libc::gid_t> = vec![0; 64];
+    loop {
+        let mut n = groups.len() as libc::c_int;
+        let res = unsafe { libc::getgrouplist(cname.as_ptr(), gid as _, groups.as_mut_ptr() as *mut _, &mut n) };
+        if res >= 0 {
+            groups.truncate(n as usize);
+            return groups;
+        }
+        if groups.len() >= 65536 {
+            return vec![gid];
+        }
+        let len = groups.len() * 2;
+        groups.resize(len, 0);
+    }
+}
+
+/// The name of the user running loginw, for user_allowed
+pub fn user_name(pw: &libc::passwd) -> String {
+    unsafe { CStr::from_ptr(pw.pw_name) }.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_file_is_the_default() {
+        let policy = Policy::parse("# nothing here\n\n   \n").unwrap();
+        assert_eq!(policy.device_kinds, vec![DeviceKind::Input, DeviceKind::Drm]);
+        assert!(policy.users.is_none() && policy.groups.is_none());
+        assert!(policy.power_allowed(PowerAction::PowerOff, false).is_ok());
+        assert!(policy.user_allowed("anyone", 65534));
+    }
+
+    #[test]
+    fn lists_and_comments() {
+        let policy = Policy::parse("devices = input ,, # no drm\ndrm_nodes=render\nusers = alice,bob\npower = no\n").unwrap();
+        assert_eq!(policy.device_kinds, vec![DeviceKind::Input]);
+        assert!(policy.device_allowed(DeviceKind::Input, None));
+        assert!(!policy.device_allowed(DeviceKind::Drm, Some(DrmNode::Render)));
+        assert_eq!(policy.users, Some(vec!["alice".to_owned(), "bob".to_owned()]));
+        assert!(policy.user_allowed("bob", 0));
+        assert!(!policy.user_allowed("mallory", 0));
+        assert!(policy.power_allowed(PowerAction::Suspend, true).is_err());
+    }
+
+    #[test]
+    fn drm_nodes() {
+        let policy = Policy::parse("drm_nodes = primary").unwrap();
+        assert!(policy.device_allowed(DeviceKind::Drm, Some(DrmNode::Primary)));
+        assert!(!policy.device_allowed(DeviceKind::Drm, Some(DrmNode::Render)));
+    }
+
+    #[test]
+    fn malformed_lines() {
+        let err = Policy::parse("users = alice\njust some words\n").err().unwrap();
+        assert!(err.starts_with("line 2:"), "{}", err);
+        assert!(Policy::parse("devices = input, mouse").is_err());
+        assert!(Policy::parse("drm_nodes = card").is_err());
+        assert!(Policy::parse("power = maybe").is_err());
+        assert!(Policy::parse("reboot = active, alone, sometimes").is_err());
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let err = Policy::parse("device = input").err().unwrap();
+        assert!(err.contains("unknown key 'device'"), "{}", err);
+        assert!(Policy::parse("= yes").is_err());
+    }
+
+    #[test]
+    fn group_membership() {
+        let pw = unsafe { &*libc::getpwuid(libc::getuid()) };
+        let gr = unsafe { libc::getgrgid(pw.pw_gid) };
+        assert!(!gr.is_null());
+        let group = unsafe { CStr::from_ptr((*gr).gr_name) }.to_string_lossy().into_owned();
+        let name = user_name(pw);
+        let policy = Policy::parse(&format!("users = loginw-test-no-such-user\ngroups = loginw-test-no-such-group, {}", group)).unwrap();
+        assert!(policy.user_allowed(&name, pw.pw_gid));
+        let policy = Policy::parse("groups = loginw-test-no-such-group").unwrap();
+        assert!(!policy.user_allowed(&name, pw.pw_gid));
+        let mut policy = Policy::parse(&format!("power = group:{}\nsuspend = group:loginw-test-no-such-group", group)).unwrap();
+        policy.prepare(&name, pw.pw_gid);
+        assert!(policy.power_allowed(PowerAction::Reboot, false).is_ok());
+        assert!(policy.power_allowed(PowerAction::Suspend, false).is_err());
+    }
+
+    #[test]
+    fn power_rules() {
+        let policy = Policy::parse("power = no\nsuspend = active\nreboot = group:operator, group:wheel, alone").unwrap();
+        assert!(policy.power_allowed(PowerAction::PowerOff, true).is_err());
+        assert!(policy.power_allowed(PowerAction::Hibernate, true).is_err());
+        assert!(policy.power_allowed(PowerAction::Suspend, false).is_err());
+        assert!(policy.power_allowed(PowerAction::Suspend, true).is_ok());
+        let reboot = policy.power_rule(PowerAction::Reboot);
+        assert!(reboot.allowed && reboot.require_alone && !reboot.require_active);
+        assert_eq!(reboot.groups, vec!["operator".to_owned(), "wheel".to_owned()]);
+    }
+
+    #[test]
+    fn alone_without_utmp_is_denied() {
+        let policy = Policy::parse("power = alone").unwrap();
+        let err = policy.power_allowed(PowerAction::Reboot, true).err().unwrap();
+        assert!(err.contains("no other users"), "{}", err);
+    }
+
+    #[test]
+    fn group_conditions() {
+        let mut policy = Policy::parse("power_off = group:loginw-test-no-such-group\nreboot = yes").unwrap();
+        policy.prepare("loginw-test-no-such-user", 65534);
+        let err = policy.power_allowed(PowerAction::PowerOff, true).err().unwrap();
+        assert!(err.contains("loginw-test-no-such-group"), "{}", err);
+        assert!(policy.power_allowed(PowerAction::Reboot, true).is_ok());
+    }
+}
@@ -0,0 +1,263 @@
+use std::{env, io, process, ptr};
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::process::Command;
+use libc;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PowerAction {
+    PowerOff = 0,
+    Reboot = 1,
+    Suspend = 2,
+    Hibernate = 3,
+}
+
+impl PowerAction {
+    fn from_u8(x: u8) -> Option<PowerAction> {
+        match x {
+            0 => Some(PowerAction::PowerOff),
+            1 => Some(PowerAction::Reboot),
+            2 => Some(PowerAction::Suspend),
+            3 => Some(PowerAction::Hibernate),
+            _ => None,
+        }
+    }
+}
+
+pub trait PowerBackend {
+    /// Whether the action is supported by the machine
+    fn can(&self, action: PowerAction) -> bool;
+    fn perform(&mut self, action: PowerAction) -> io::Result<()>;
+}
+
+/// Picks the backend for the current OS, or the test one if LOGINW_POWER_BACKEND=test.
+/// Must be called before sandboxing and before forking the child.
+pub fn backend_from_env() -> Box<dyn PowerBackend> {
+    match env::var("LOGINW_POWER_BACKEND") {
+        Ok(ref s) if s == "test" => Box::new(TestBackend::new()),
+        _ => Box::new(os_backend()),
+    }
+}
+
+/// Does nothing, only remembers what was requested
+pub struct TestBackend {
+    pub calls: Vec<PowerAction>,
+}
+
+impl TestBackend {
+    pub fn new() -> TestBackend {
+        TestBackend { calls: Vec::new() }
+    }
+}
+
+impl PowerBackend for TestBackend {
+    fn can(&self, _action: PowerAction) -> bool {
+        true
+    }
+
+    fn perform(&mut self, action: PowerAction) -> io::Result<()> {
+        info!("test power backend: {:?} (calls so far: {:?})", action, self.calls);
+        self.calls.push(action);
+        Ok(())
+    }
+}
+
+/// The only environment the commands get
+const COMMAND_PATH: &str = "/sbin:/bin:/usr/sbin:/usr/bin";
+
+/// Runs power management commands in a helper process forked before sandboxing,
+/// since the sandboxed server can't exec anything.
+struct CommandRunner {
+    sock: UnixStream,
+    pid: libc::pid_t,
+}
+
+impl Drop for CommandRunner {
+    fn drop(&mut self) {
+        // the helper exits when the socket is closed
+        let _ = self.sock.shutdown(Shutdown::Both);
+        let mut status = 0;
+        unsafe { libc::waitpid(self.pid, &mut status, 0) };
+    }
+}
+
+impl CommandRunner {
+    fn new(commands: fn(PowerAction) -> Vec<&'static str>) -> CommandRunner {
+        let (parent, mut child) = UnixStream::pair().expect("socketpair");
+        match unsafe { libc::fork() } {
+            -1 => panic!("fork"),
+            0 => {
+                drop(parent);
+                // Fully root, real ids included, so exec is no longer AT_SECURE:
+                // the commands must not get anything from the user's environment (LD_PRELOAD…)
+                if unsafe { libc::setgroups(0, ptr::null()) } != 0
+                    || unsafe { libc::setgid(0) } != 0
+                    || unsafe { libc::setuid(0) } != 0
+                {
+                    error!("power helper: could not become root: {}", io::Error::last_os_error());
+                    process::exit(1);
+                }
+                let mut buf = [0u8; 1];
+                while let Ok(1) = child.read(&mut buf) {
+                    let ok = match PowerAction::from_u8(buf[0]) {
+                        Some(action) => {
+                            let argv = commands(action);
+                            info!("running {:?}", argv);
+                            Command::new(argv[0])
+                                .args(&argv[1..])
+                                .env_clear()
+                                .env("PATH", COMMAND_PATH)
+                                .status()
+                                .map(|s| s.success())
+                                .unwrap_or(false)
+                        },
+                        None => false,
+                    };
+                    if child.write_all(&[ok as u8]).is_err() {
+                        break;
+                    }
+                }
+                process::exit(0);
+            },
+            pid => CommandRunner { sock: parent, pid },
+        }
+    }
+
+    fn run(&mut self, action: PowerAction) -> io::Result<()> {
+        self.sock.write_all(&[action as u8])?;
+        let mut buf = [0u8; 1];
+        self.sock.read_exact(&mut buf)?;
+        if buf[0] == 1 {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!("{:?} command failed", action)))
+        }
+    }
+}
+
+#[cfg(target_os = "freebsd")]
+fn os_backend() -> FreeBsdBackend {
+    FreeBsdBackend::new()
+}
+
+#[cfg(target_os = "linux")]
+fn os_backend() -> LinuxBackend {
+    LinuxBackend::new()
+}
+
+/// acpiconf for sleep states, shutdown for the rest
+#[cfg(target_os = "freebsd")]
+pub struct FreeBsdBackend {
+    runner: CommandRunner,
+    sleep_states: String,
+}
+
+#[cfg(target_os = "freebsd")]
+impl FreeBsdBackend {
+    pub fn new() -> FreeBsdBackend {
+        FreeBsdBackend {
+            runner: CommandRunner::new(|action| match action {
+                PowerAction::PowerOff => vec!["/sbin/shutdown", "-p", "now"],
+                PowerAction::Reboot => vec!["/sbin/shutdown", "-r", "now"],
+                PowerAction::Suspend => vec!["/usr/sbin/acpiconf", "-s", "3"],
+                PowerAction::Hibernate => vec!["/usr/sbin/acpiconf", "-s", "4"],
+            }),
+            sleep_states: sysctl_string("hw.acpi.supported_sleep_state").unwrap_or_else(String::new),
+        }
+    }
+}
+
+#[cfg(target_os = "freebsd")]
+impl PowerBackend for FreeBsdBackend {
+    fn can(&self, action: PowerAction) -> bool {
+        match action {
+            PowerAction::PowerOff | PowerAction::Reboot => true,
+            PowerAction::Suspend => self.sleep_states.split_whitespace().any(|s| s == "S3"),
+            PowerAction::Hibernate => self.sleep_states.split_whitespace().any(|s| s == "S4"),
+        }
+    }
+
+    fn perform(&mut self, action: PowerAction) -> io::Result<()> {
+        if !self.can(action) {
+            return Err(io::Error::other(format!("{:?} is not supported", action)));
+        }
+        self.runner.run(action)
+    }
+}
+
+#[cfg(target_os = "freebsd")]
+fn sysctl_string(name: &str) -> Option<String> {
+    use std::ffi::CString;
+    let cname = CString::new(name).ok()?;
+    let mut len = 0;
+    if unsafe { libc::sysctlbyname(cname.as_ptr(), ptr::null_mut(), &mut len, ptr::null(), 0) } != 0 {
+        return None;
+    }
+    let mut buf = vec![0u8; len];
+    if unsafe { libc::sysctlbyname(cname.as_ptr(), buf.as_mut_ptr() as *mut _, &mut len, ptr::null(), 0) } != 0 {
+        return None;
+    }
+    buf.truncate(len);
+    while buf.last() == Some(&0) {
+        buf.pop();
+    }
+    String::from_utf8(buf).ok()
+}
+
+/// /sys/power/state for sleep states, shutdown for the rest
+#[cfg(target_os = "linux")]
+pub struct LinuxBackend {
+    runner: CommandRunner,
+    state_file: Option<::std::fs::File>,
+    sleep_states: String,
+}
+
+#[cfg(target_os = "linux")]
+impl LinuxBackend {
+    pub fn new() -> LinuxBackend {
+        use std::fs::OpenOptions;
+        let mut sleep_states = String::new();
+        let state_file = OpenOptions::new().read(true).write(true).open("/sys/power/state").ok();
+        if let Some(mut f) = state_file.as_ref() {
+            let _ = f.read_to_string(&mut sleep_states);
+        }
+        LinuxBackend {
+            runner: CommandRunner::new(|action| match action {
+                PowerAction::Reboot => vec!["/sbin/shutdown", "-r", "now"],
+                _ => vec!["/sbin/shutdown", "-P", "now"],
+            }),
+            state_file,
+            sleep_states,
+        }
+    }
+
+    fn write_state(&mut self, state: &str) -> io::Result<()> {
+        match self.state_file {
+            Some(ref mut f) => f.write_all(state.as_bytes()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "/sys/power/state is not available")),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl PowerBackend for LinuxBackend {
+    fn can(&self, action: PowerAction) -> bool {
+        match action {
+            PowerAction::PowerOff | PowerAction::Reboot => true,
+            PowerAction::Suspend => self.state_file.is_some() && self.sleep_states.split_whitespace().any(|s| s == "mem"),
+            PowerAction::Hibernate => self.state_file.is_some() && self.sleep_states.split_whitespace().any(|s| s == "disk"),
+        }
+    }
+
+    fn perform(&mut self, action: PowerAction) -> io::Result<()> {
+        if !self.can(action) {
+            return Err(io::Error::other(format!("{:?} is not supported", action)));
+        }
+        match action {
+            PowerAction::PowerOff | PowerAction::Reboot => self.runner.run(action),
+            PowerAction::Suspend => self.write_state("mem"),
+            PowerAction::Hibernate => self.write_state("disk"),
+        }
+    }
+}
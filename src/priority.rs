@@ -1,3 +1,4 @@
+#[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
 use libc;
 
 #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
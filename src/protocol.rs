@@ -1,11 +1,84 @@
 #![allow(dead_code)]
 // cbindgen can't add a prefix to everything, so we have Loginw* names here :(
 
+/// Bumped on every change to the structs or enums below.
+/// Version 0 is the original layout, where LoginwResponse had no error info.
+pub const LOGINW_PROTOCOL_VERSION: u32 = 6;
+
+// Capability bits returned by LoginwHello, one per request type
+pub const LOGINW_CAP_OPEN_INPUT: u64 = 1 << 0;
+pub const LOGINW_CAP_OPEN_DRM: u64 = 1 << 1;
+pub const LOGINW_CAP_ACQUIRE_VT: u64 = 1 << 2;
+pub const LOGINW_CAP_SWITCH_VT: u64 = 1 << 3;
+pub const LOGINW_CAP_POWER_OFF: u64 = 1 << 4;
+pub const LOGINW_CAP_REBOOT: u64 = 1 << 5;
+pub const LOGINW_CAP_SUSPEND: u64 = 1 << 6;
+pub const LOGINW_CAP_HIBERNATE: u64 = 1 << 7;
+pub const LOGINW_CAP_CAN_SUSPEND: u64 = 1 << 8;
+pub const LOGINW_CAP_CAN_HIBERNATE: u64 = 1 << 9;
+pub const LOGINW_CAP_HELLO: u64 = 1 << 10;
+pub const LOGINW_CAP_CLOSE_DEVICE: u64 = 1 << 11;
+pub const LOGINW_CAP_OPEN_DEVICE: u64 = 1 << 12;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct LoginwHelloData {
+    /// LOGINW_PROTOCOL_VERSION of the sender
+    pub version: u32,
+    /// LOGINW_CAP_* bits of the implemented request types (server only)
+    pub capabilities: u64,
+}
+
+/// Device classes for LoginwOpenDevice
+#[repr(u16)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[allow(clippy::enum_variant_names)]
+pub enum LoginwDeviceClass {
+    /// evdev (/dev/input/event*), revoked on VT release
+    LoginwDeviceInput = 0,
+    /// DRM (/dev/dri/*), DRM master is dropped on VT release
+    LoginwDeviceDrm = 1,
+    /// Sound (/dev/dsp*, /dev/snd/*), kept open on VT release
+    LoginwDeviceSound = 2,
+    /// video4linux (/dev/video*), kept open on VT release
+    LoginwDeviceVideo = 3,
+    /// Raw HID (/dev/hidraw*), only where the kernel can revoke it on VT release (Linux 6.12)
+    LoginwDeviceHidraw = 4,
+    /// Framebuffer (/dev/fb*), kept open on VT release, stop drawing when deactivated
+    LoginwDeviceFramebuffer = 5,
+}
+
+impl LoginwDeviceClass {
+    pub fn from_u16(x: u16) -> Option<LoginwDeviceClass> {
+        use self::LoginwDeviceClass::*;
+        match x {
+            0 => Some(LoginwDeviceInput),
+            1 => Some(LoginwDeviceDrm),
+            2 => Some(LoginwDeviceSound),
+            3 => Some(LoginwDeviceVideo),
+            4 => Some(LoginwDeviceHidraw),
+            5 => Some(LoginwDeviceFramebuffer),
+            _ => None,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct LoginwOpenDeviceData {
+    /// A LoginwDeviceClass value
+    pub device_class: u16,
+    /// NUL-terminated full path
+    pub path: [u8; 126],
+}
+
 #[repr(C)]
 pub union LoginwData {
     pub bytes: [u8; 128],
     pub u64: u64,
     pub boolean: bool,
+    pub hello: LoginwHelloData,
+    pub open_device: LoginwOpenDeviceData,
 }
 
 impl Default for LoginwData {
@@ -16,13 +89,19 @@ impl Default for LoginwData {
 
 #[repr(u16)]
 #[derive(Debug, Copy, Clone, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 pub enum LoginwRequestType {
-    /// bytes -> fd -- Open an input (evdev) device fd (by full path)
+    /// bytes -> u64 + fd -- Open an input (evdev) device fd (by full path), returns the device id
     LoginwOpenInput = 0,
-    /// bytes -> fd -- Open a DRM device fd (by full path)
+    /// bytes -> u64 + fd -- Open a DRM device fd (by full path), returns the device id
     LoginwOpenDrm = 1,
+    /// u64 -> void -- Close a device (by device id), e.g. after hotplug removal
+    LoginwCloseDevice = 2,
+    /// open_device -> u64 + fd -- Open a device of the given class (by full path), returns the device id
+    LoginwOpenDevice = 3,
 
-    /// void -> u64 + fd -- Initialize a new virtual terminal, returns vt number and passes tty fd
+    /// u64 -> u64 + fd -- Initialize a virtual terminal (by number, must be free or loginw's own; 0 for the default),
+    /// returns vt number and passes tty fd
     LoginwAcquireVt = 100,
     /// uint -> void -- Switch to a given virtual terminal (by number)
     LoginwSwitchVt = 101,
@@ -40,6 +119,50 @@ pub enum LoginwRequestType {
     LoginwCanSuspend = 302,
     /// void -> boolean -- Checks whether hibernation is possible
     LoginwCanHibernate = 303,
+
+    /// hello -> hello -- Exchanges protocol versions, returns the server's capabilities
+    LoginwHello = 1000,
+}
+
+impl LoginwRequestType {
+    pub fn from_u16(x: u16) -> Option<LoginwRequestType> {
+        use self::LoginwRequestType::*;
+        match x {
+            0 => Some(LoginwOpenInput),
+            1 => Some(LoginwOpenDrm),
+            2 => Some(LoginwCloseDevice),
+            3 => Some(LoginwOpenDevice),
+            100 => Some(LoginwAcquireVt),
+            101 => Some(LoginwSwitchVt),
+            200 => Some(LoginwPowerOff),
+            201 => Some(LoginwReboot),
+            202 => Some(LoginwSuspend),
+            203 => Some(LoginwHibernate),
+            302 => Some(LoginwCanSuspend),
+            303 => Some(LoginwCanHibernate),
+            1000 => Some(LoginwHello),
+            _ => None,
+        }
+    }
+
+    pub fn capability(&self) -> u64 {
+        use self::LoginwRequestType::*;
+        match *self {
+            LoginwOpenInput => LOGINW_CAP_OPEN_INPUT,
+            LoginwOpenDrm => LOGINW_CAP_OPEN_DRM,
+            LoginwCloseDevice => LOGINW_CAP_CLOSE_DEVICE,
+            LoginwOpenDevice => LOGINW_CAP_OPEN_DEVICE,
+            LoginwAcquireVt => LOGINW_CAP_ACQUIRE_VT,
+            LoginwSwitchVt => LOGINW_CAP_SWITCH_VT,
+            LoginwPowerOff => LOGINW_CAP_POWER_OFF,
+            LoginwReboot => LOGINW_CAP_REBOOT,
+            LoginwSuspend => LOGINW_CAP_SUSPEND,
+            LoginwHibernate => LOGINW_CAP_HIBERNATE,
+            LoginwCanSuspend => LOGINW_CAP_CAN_SUSPEND,
+            LoginwCanHibernate => LOGINW_CAP_CAN_HIBERNATE,
+            LoginwHello => LOGINW_CAP_HELLO,
+        }
+    }
 }
 
 #[repr(C)]
@@ -56,6 +179,7 @@ impl LoginwRequest {
 
 #[repr(u16)]
 #[derive(Debug, Copy, Clone, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 pub enum LoginwResponseType {
     LoginwError = 0,
     LoginwDone = 1,
@@ -64,21 +188,93 @@ pub enum LoginwResponseType {
     // Notifications (not actually responses)
     LoginwActivated = 100,
     LoginwDeactivated = 101,
+    /// u64 -- The device with this id was revoked on VT release, its fd is dead
+    LoginwDevicePaused = 102,
+    /// u64 + fd -- The device with this id was reopened on VT acquire, use the new fd
+    LoginwDeviceResumed = 103,
+}
+
+impl LoginwResponseType {
+    pub fn from_u16(x: u16) -> Option<LoginwResponseType> {
+        use self::LoginwResponseType::*;
+        match x {
+            0 => Some(LoginwError),
+            1 => Some(LoginwDone),
+            2 => Some(LoginwPassedFd),
+            100 => Some(LoginwActivated),
+            101 => Some(LoginwDeactivated),
+            102 => Some(LoginwDevicePaused),
+            103 => Some(LoginwDeviceResumed),
+            _ => None,
+        }
+    }
+}
+
+#[repr(u16)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[allow(clippy::enum_variant_names)]
+pub enum LoginwErrorCode {
+    /// Not an error
+    LoginwErrNone = 0,
+    /// A system call failed, sys_errno has the errno value
+    LoginwErrErrno = 1,
+    /// The path is not a device of the requested kind
+    LoginwErrNotADevice = 2,
+    /// The request needs a VT, but LoginwAcquireVt was not done yet
+    LoginwErrNoVt = 3,
+    /// The request was denied by the access policy
+    LoginwErrDenied = 4,
+    /// The request type is unknown or not implemented
+    LoginwErrUnsupported = 5,
+    /// The request data is malformed
+    LoginwErrBadData = 6,
+    /// The action is not available on this machine
+    LoginwErrUnavailable = 7,
+    /// Something else went wrong, see the message
+    LoginwErrFailed = 8,
+}
+
+impl LoginwErrorCode {
+    /// Unknown codes (from a newer server) are reported as LoginwErrFailed
+    pub fn from_u16(x: u16) -> LoginwErrorCode {
+        use self::LoginwErrorCode::*;
+        match x {
+            0 => LoginwErrNone,
+            1 => LoginwErrErrno,
+            2 => LoginwErrNotADevice,
+            3 => LoginwErrNoVt,
+            4 => LoginwErrDenied,
+            5 => LoginwErrUnsupported,
+            6 => LoginwErrBadData,
+            7 => LoginwErrUnavailable,
+            _ => LoginwErrFailed,
+        }
+    }
 }
 
+/// The error fields occupy what used to be padding after typ,
+/// so the size and the offset of dat are the same as in version 0.
 #[repr(C)]
 pub struct LoginwResponse {
     pub typ: LoginwResponseType,
+    /// Error category (LoginwError responses only)
+    pub err: LoginwErrorCode,
+    /// errno value when err is LoginwErrErrno
+    pub sys_errno: i32,
+    /// For LoginwError: optional NUL-terminated message in bytes
     pub dat: LoginwData,
 }
 
 impl LoginwResponse {
     pub fn new(typ: LoginwResponseType) -> LoginwResponse {
-        LoginwResponse { typ, dat: LoginwData::default() }
+        LoginwResponse { typ, err: LoginwErrorCode::LoginwErrNone, sys_errno: 0, dat: LoginwData::default() }
+    }
+
+    pub fn error(err: LoginwErrorCode, sys_errno: i32) -> LoginwResponse {
+        LoginwResponse { typ: LoginwResponseType::LoginwError, err, sys_errno, dat: LoginwData::default() }
     }
 }
 
-#[allow(private_no_mangle_fns)]
 #[no_mangle]
 pub extern "C" fn _cbindgen_helper(
     _a: LoginwData,
@@ -86,5 +282,9 @@ pub extern "C" fn _cbindgen_helper(
     _c: LoginwRequest,
     _d: LoginwResponseType,
     _e: LoginwResponse,
+    _f: LoginwErrorCode,
+    _g: LoginwHelloData,
+    _h: LoginwDeviceClass,
+    _i: LoginwOpenDeviceData,
 ) {
 }
@@ -0,0 +1,156 @@
+//! Supervisor mode: the command runs under a launcher process, forked before sandboxing so that it can
+//! still exec, which restarts the command after crashes and hands loginw a fresh socket for each instance.
+//! loginw itself keeps the VT and the DRM devices in the meantime.
+
+use std::{process, str};
+use std::collections::VecDeque;
+use std::os::unix::io::{RawFd, AsRawFd};
+use std::time::{Duration, Instant};
+use libc;
+#[cfg(target_os = "freebsd")]
+use pdfork::*;
+#[cfg(target_os = "linux")]
+use pidfd::*;
+use tiny_nix_ipc::Socket;
+use nix::sys::signal::Signal;
+use evloop::{self, Event, EventLoop};
+use exit_code;
+
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const SIGNALS: &[Signal] = &[Signal::SIGINT, Signal::SIGTERM];
+
+/// Give up after max_crashes crashes within window
+#[derive(Debug, Copy, Clone)]
+pub struct Limits {
+    pub max_crashes: usize,
+    pub window: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits { max_crashes: 5, window: Duration::from_secs(60) }
+    }
+}
+
+impl str::FromStr for Limits {
+    type Err = ();
+
+    /// CRASHES/SECONDS
+    fn from_str(s: &str) -> Result<Limits, ()> {
+        let mut parts = s.splitn(2, '/');
+        let max_crashes = parts.next().ok_or(())?.parse::<usize>().map_err(drop)?;
+        let secs = parts.next().ok_or(())?.parse::<u64>().map_err(drop)?;
+        if max_crashes < 1 {
+            return Err(());
+        }
+        Ok(Limits { max_crashes, window: Duration::from_secs(secs) })
+    }
+}
+
+/// Sent by the launcher along with loginw's end of the new instance's socket
+#[repr(C)]
+pub struct Respawned {
+    /// Number of restarts so far
+    pub count: u32,
+}
+
+/// A clean exit is a logout, not a crash
+fn crashed(status: libc::c_int) -> bool {
+    !(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0)
+}
+
+fn spawn<F: Fn(RawFd)>(exec: &F, sock: &Socket) -> ChildHandle {
+    match fork() {
+        ForkResult::Fail => panic!("fork"),
+        ForkResult::Parent(child) => child,
+        ForkResult::Child => {
+            evloop::reset_signals(SIGNALS);
+            exec(sock.as_raw_fd());
+            process::exit(127);
+        },
+    }
+}
+
+/// The launcher process: runs the command until it exits cleanly, is stopped by a signal
+/// (forwarded from loginw) or crashes too often, then exits with its status.
+/// exec must not return on success.
+pub fn run<F: Fn(RawFd)>(mut ctl: Socket, first: Socket, limits: Limits, exec: F) -> ! {
+    let mut evloop = EventLoop::new().expect("EventLoop::new");
+    evloop.watch_signals(SIGNALS).expect("watch_signals");
+    let mut sock = first;
+    let mut crashes: VecDeque<Instant> = VecDeque::new();
+    let mut count = 0;
+    loop {
+        let child = spawn(&exec, &sock);
+        drop(sock);
+        evloop.watch_proc(&child).expect("watch_proc");
+        let mut stopping = false;
+        let status = loop {
+            match evloop.wait().expect("wait") {
+                Event::Signal(sig) => {
+                    info!("launcher: forwarding {:?}", sig);
+                    stopping = true;
+                    let _ = child.signal(sig as libc::c_int);
+                },
+                Event::ProcExit(status) => break status,
+                _ => (),
+            }
+        };
+        drop(child);
+        if stopping || !crashed(status) {
+            process::exit(exit_code(status));
+        }
+        let now = Instant::now();
+        crashes.push_back(now);
+        while crashes.front().map(|t| now.duration_since(*t) > limits.window).unwrap_or(false) {
+            crashes.pop_front();
+        }
+        if crashes.len() >= limits.max_crashes {
+            error!("command crashed {} times within {:?} (last status {}), giving up", crashes.len(), limits.window, status);
+            process::exit(exit_code(status));
+        }
+        let backoff = (FIRST_BACKOFF * 2u32.pow((crashes.len() - 1).min(8) as u32)).min(MAX_BACKOFF);
+        warn!("command crashed (status {}), restarting in {:?}", status, backoff);
+        evloop.set_timeout(backoff);
+        loop {
+            match evloop.wait().expect("wait") {
+                Event::Timeout => break,
+                Event::Signal(sig) => {
+                    info!("launcher: {:?} while waiting to restart, exiting", sig);
+                    process::exit(exit_code(status));
+                },
+                _ => (),
+            }
+        }
+        let (sock_loginw, mut sock_child) = Socket::new_socketpair().expect("socketpair");
+        sock_child.no_cloexec().expect("no_cloexec");
+        count += 1;
+        if let Err(e) = ctl.send_struct(&Respawned { count }, Some(&[sock_loginw.as_raw_fd()][..])) {
+            error!("launcher: could not pass the new socket to loginw: {}", e);
+            process::exit(exit_code(status));
+        }
+        drop(sock_loginw);
+        sock = sock_child;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_limits() {
+        let limits = "3/10".parse::<Limits>().unwrap();
+        assert_eq!(limits.max_crashes, 3);
+        assert_eq!(limits.window, Duration::from_secs(10));
+        assert_eq!("1/0".parse::<Limits>().unwrap().window, Duration::from_secs(0));
+    }
+
+    #[test]
+    fn parse_bad_limits() {
+        for s in &["", "3", "3/", "/10", "0/10", "-1/10", "x/10", "3/x", "3/-10", "3/10/5", "3 / 10"] {
+            assert!(s.parse::<Limits>().is_err(), "{:?}", s);
+        }
+    }
+}
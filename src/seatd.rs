@@ -0,0 +1,308 @@
+//! The seatd wire protocol (server side), so that unmodified libseat clients can run under loginw.
+//! Messages are a header of u16 opcode + u16 body size, followed by the body, in native byte order.
+
+use std::ffi::CStr;
+use std::os::unix::io::RawFd;
+use nix;
+use nix::errno::Errno;
+use nix::unistd;
+use nix::sys::uio::IoVec;
+use nix::sys::socket::*;
+
+const CLIENT_OPEN_SEAT: u16 = 1;
+const CLIENT_CLOSE_SEAT: u16 = 2;
+const CLIENT_OPEN_DEVICE: u16 = 3;
+const CLIENT_CLOSE_DEVICE: u16 = 4;
+const CLIENT_DISABLE_SEAT: u16 = 5;
+const CLIENT_SWITCH_SESSION: u16 = 6;
+const CLIENT_PING: u16 = 7;
+
+const SERVER_SEAT_OPENED: u16 = 1 | 1 << 15;
+const SERVER_SEAT_CLOSED: u16 = 2 | 1 << 15;
+const SERVER_DEVICE_OPENED: u16 = 3 | 1 << 15;
+const SERVER_DEVICE_CLOSED: u16 = 4 | 1 << 15;
+const SERVER_DISABLE_SEAT: u16 = 5 | 1 << 15;
+const SERVER_ENABLE_SEAT: u16 = 6 | 1 << 15;
+const SERVER_PONG: u16 = 7 | 1 << 15;
+const SERVER_ERROR: u16 = 0x7FFF | 1 << 15;
+
+const HEADER_SIZE: usize = 4;
+pub const SOCKET_NAME: &str = "seatd.sock";
+pub const SEAT_NAME: &str = "seat0";
+
+#[derive(Debug)]
+pub enum Request {
+    OpenSeat,
+    CloseSeat,
+    OpenDevice(String),
+    CloseDevice(i32),
+    DisableSeat,
+    SwitchSession(i32),
+    Ping,
+    /// Unknown opcode or malformed body
+    Invalid(u16),
+}
+
+pub struct Connection {
+    pub fd: RawFd,
+    buf: Vec<u8>,
+}
+
+impl Connection {
+    /// Takes ownership of an accepted, nonblocking fd
+    pub fn new(fd: RawFd) -> Connection {
+        Connection { fd, buf: Vec::new() }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = unistd::close(self.fd);
+    }
+}
+
+fn read_u16(buf: &[u8]) -> u16 {
+    let mut x = [0u8; 2];
+    x.copy_from_slice(&buf[..2]);
+    u16::from_ne_bytes(x)
+}
+
+fn read_i32(buf: &[u8]) -> i32 {
+    let mut x = [0u8; 4];
+    x.copy_from_slice(&buf[..4]);
+    i32::from_ne_bytes(x)
+}
+
+fn parse(opcode: u16, body: &[u8]) -> Request {
+    match opcode {
+        CLIENT_OPEN_SEAT => Request::OpenSeat,
+        CLIENT_CLOSE_SEAT => Request::CloseSeat,
+        CLIENT_OPEN_DEVICE if body.len() >= 2 => {
+            // u16 length (including the NUL), then the NUL-terminated path
+            let len = read_u16(body) as usize;
+            if len == 0 || body.len() < 2 + len {
+                return Request::Invalid(opcode);
+            }
+            match CStr::from_bytes_with_nul(&body[2..2 + len]).ok().and_then(|s| s.to_str().ok()) {
+                Some(path) => Request::OpenDevice(path.to_owned()),
+                None => Request::Invalid(opcode),
+            }
+        },
+        CLIENT_CLOSE_DEVICE if body.len() >= 4 => Request::CloseDevice(read_i32(body)),
+        CLIENT_DISABLE_SEAT => Request::DisableSeat,
+        CLIENT_SWITCH_SESSION if body.len() >= 4 => Request::SwitchSession(read_i32(body)),
+        CLIENT_PING => Request::Ping,
+        _ => Request::Invalid(opcode),
+    }
+}
+
+impl Connection {
+    /// Reads what's available into the buffer, returns false when the client is gone
+    pub fn fill(&mut self) -> bool {
+        let mut chunk = [0u8; 1024];
+        loop {
+            match unistd::read(self.fd, &mut chunk) {
+                Ok(0) => return false,
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(nix::Error::Sys(Errno::EAGAIN)) => return true,
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                Err(e) => {
+                    warn!("seatd client read: {}", e);
+                    return false;
+                },
+            }
+        }
+    }
+
+    /// Takes the next complete message from the buffer
+    pub fn next_request(&mut self) -> Option<Request> {
+        if self.buf.len() < HEADER_SIZE {
+            return None;
+        }
+        let opcode = read_u16(&self.buf[0..]);
+        let size = read_u16(&self.buf[2..]) as usize;
+        if self.buf.len() < HEADER_SIZE + size {
+            return None;
+        }
+        let req = parse(opcode, &self.buf[HEADER_SIZE..HEADER_SIZE + size]);
+        self.buf.drain(..HEADER_SIZE + size);
+        debug!("seatd request: {:?}", req);
+        Some(req)
+    }
+
+    fn send(&mut self, opcode: u16, body: &[u8], fd: Option<RawFd>) {
+        let mut msg = Vec::with_capacity(HEADER_SIZE + body.len());
+        msg.extend_from_slice(&u16::to_ne_bytes(opcode));
+        msg.extend_from_slice(&u16::to_ne_bytes(body.len() as u16));
+        msg.extend_from_slice(body);
+        let fds = fd.map(|fd| [fd]);
+        let cmsgs = match fds {
+            Some(ref fds) => vec![ControlMessage::ScmRights(&fds[..])],
+            None => vec![],
+        };
+        if let Err(e) = sendmsg(self.fd, &[IoVec::from_slice(&msg)], &cmsgs, MsgFlags::empty(), None) {
+            error!("seatd client sendmsg: {}", e);
+        }
+    }
+
+    pub fn seat_opened(&mut self) {
+        let mut body = Vec::new();
+        body.extend_from_slice(&u16::to_ne_bytes(SEAT_NAME.len() as u16 + 1));
+        body.extend_from_slice(SEAT_NAME.as_bytes());
+        body.push(0);
+        self.send(SERVER_SEAT_OPENED, &body, None)
+    }
+
+    pub fn seat_closed(&mut self) {
+        self.send(SERVER_SEAT_CLOSED, &[], None)
+    }
+
+    pub fn device_opened(&mut self, device_id: i32, fd: RawFd) {
+        self.send(SERVER_DEVICE_OPENED, &i32::to_ne_bytes(device_id), Some(fd))
+    }
+
+    pub fn device_closed(&mut self) {
+        self.send(SERVER_DEVICE_CLOSED, &[], None)
+    }
+
+    pub fn disable_seat(&mut self) {
+        self.send(SERVER_DISABLE_SEAT, &[], None)
+    }
+
+    pub fn enable_seat(&mut self) {
+        self.send(SERVER_ENABLE_SEAT, &[], None)
+    }
+
+    pub fn pong(&mut self) {
+        self.send(SERVER_PONG, &[], None)
+    }
+
+    pub fn error(&mut self, errno: i32) {
+        self.send(SERVER_ERROR, &i32::to_ne_bytes(errno), None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc;
+
+    /// A connection with the client's end of a socketpair
+    fn connection() -> (Connection, RawFd) {
+        let (ours, theirs) = socketpair(AddressFamily::Unix, SockType::Stream, None, SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK).unwrap();
+        (Connection { fd: ours, buf: Vec::new() }, theirs)
+    }
+
+    /// Encodes a message like libseat does
+    fn message(opcode: u16, body: &[u8]) -> Vec<u8> {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&u16::to_ne_bytes(opcode));
+        msg.extend_from_slice(&u16::to_ne_bytes(body.len() as u16));
+        msg.extend_from_slice(body);
+        msg
+    }
+
+    fn path_body(path: &str) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&u16::to_ne_bytes(path.len() as u16 + 1));
+        body.extend_from_slice(path.as_bytes());
+        body.push(0);
+        body
+    }
+
+    fn requests(conn: &mut Connection) -> Vec<String> {
+        let mut reqs = Vec::new();
+        while let Some(req) = conn.next_request() {
+            reqs.push(format!("{:?}", req));
+        }
+        reqs
+    }
+
+    /// Reads one server message, returns the opcode and body
+    fn reply(fd: RawFd) -> (u16, Vec<u8>) {
+        let mut buf = [0u8; 256];
+        let n = unistd::read(fd, &mut buf).unwrap();
+        assert!(n >= HEADER_SIZE);
+        assert_eq!(read_u16(&buf[2..]) as usize, n - HEADER_SIZE);
+        (read_u16(&buf), buf[HEADER_SIZE..n].to_vec())
+    }
+
+    #[test]
+    fn parses_requests() {
+        let (mut conn, theirs) = connection();
+        for msg in &[
+            message(CLIENT_OPEN_SEAT, &[]),
+            message(CLIENT_OPEN_DEVICE, &path_body("/dev/input/event3")),
+            message(CLIENT_CLOSE_DEVICE, &i32::to_ne_bytes(7)),
+            message(CLIENT_SWITCH_SESSION, &i32::to_ne_bytes(2)),
+            message(CLIENT_DISABLE_SEAT, &[]),
+            message(CLIENT_PING, &[]),
+            message(CLIENT_CLOSE_SEAT, &[]),
+        ] {
+            conn.buf.extend_from_slice(msg);
+        }
+        assert_eq!(requests(&mut conn), vec![
+            "OpenSeat",
+            "OpenDevice(\"/dev/input/event3\")",
+            "CloseDevice(7)",
+            "SwitchSession(2)",
+            "DisableSeat",
+            "Ping",
+            "CloseSeat",
+        ]);
+        assert!(conn.buf.is_empty());
+        let _ = unistd::close(theirs);
+    }
+
+    #[test]
+    fn waits_for_the_whole_message() {
+        let (mut conn, theirs) = connection();
+        let msg = message(CLIENT_OPEN_DEVICE, &path_body("/dev/dri/card0"));
+        conn.buf.extend_from_slice(&msg[..2]);
+        assert!(conn.next_request().is_none());
+        conn.buf.extend_from_slice(&msg[2..msg.len() - 1]);
+        assert!(conn.next_request().is_none());
+        conn.buf.extend_from_slice(&msg[msg.len() - 1..]);
+        unistd::write(theirs, &message(CLIENT_PING, &[])).unwrap();
+        assert!(conn.fill());
+        assert_eq!(requests(&mut conn), vec!["OpenDevice(\"/dev/dri/card0\")", "Ping"]);
+        unistd::close(theirs).unwrap();
+        assert!(!conn.fill());
+    }
+
+    #[test]
+    fn rejects_malformed_bodies() {
+        let (mut conn, theirs) = connection();
+        let mut no_nul = path_body("/dev/input/event0");
+        no_nul.pop();
+        no_nul.push(b'x');
+        let mut too_long = path_body("/dev/input/event0");
+        too_long[0] += 1;
+        for msg in &[
+            message(CLIENT_OPEN_DEVICE, &no_nul),
+            message(CLIENT_OPEN_DEVICE, &too_long),
+            message(CLIENT_OPEN_DEVICE, &[0, 0]),
+            message(CLIENT_CLOSE_DEVICE, &[1, 2]),
+            message(CLIENT_SWITCH_SESSION, &[]),
+            message(42, &[]),
+        ] {
+            conn.buf.extend_from_slice(msg);
+        }
+        assert_eq!(requests(&mut conn), vec!["Invalid(3)", "Invalid(3)", "Invalid(3)", "Invalid(4)", "Invalid(6)", "Invalid(42)"]);
+        let _ = unistd::close(theirs);
+    }
+
+    #[test]
+    fn encodes_replies() {
+        let (mut conn, theirs) = connection();
+        conn.seat_opened();
+        assert_eq!(reply(theirs), (SERVER_SEAT_OPENED, path_body(SEAT_NAME)));
+        conn.device_opened(5, conn.fd);
+        assert_eq!(reply(theirs), (SERVER_DEVICE_OPENED, i32::to_ne_bytes(5).to_vec()));
+        conn.disable_seat();
+        assert_eq!(reply(theirs), (SERVER_DISABLE_SEAT, vec![]));
+        conn.error(libc::ENODEV);
+        assert_eq!(reply(theirs), (SERVER_ERROR, i32::to_ne_bytes(libc::ENODEV).to_vec()));
+        let _ = unistd::close(theirs);
+    }
+}
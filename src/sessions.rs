@@ -0,0 +1,87 @@
+//! Who else is logged in, from the utmp database.
+//! The file is opened before sandboxing and reread through the fd.
+
+use std::os::unix::io::RawFd;
+use nix::{fcntl, unistd};
+use nix::fcntl::OFlag;
+use nix::sys::{stat, uio};
+
+#[cfg(target_os = "freebsd")]
+mod sys {
+    pub const DB_PATH: &str = "/var/run/utx.active";
+    /// struct futx from libc's utxdb.h (packed)
+    pub const RECORD_SIZE: usize = 197;
+    pub const USER_OFFSET: usize = 21;
+    pub const USER_PROCESS: i16 = 4;
+
+    pub fn record_type(rec: &[u8]) -> i16 {
+        rec[0] as i16
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    pub const DB_PATH: &str = "/var/run/utmp";
+    /// struct utmp from glibc's utmp.h
+    pub const RECORD_SIZE: usize = 384;
+    pub const USER_OFFSET: usize = 44;
+    pub const USER_PROCESS: i16 = 7;
+
+    pub fn record_type(rec: &[u8]) -> i16 {
+        i16::from_ne_bytes([rec[0], rec[1]])
+    }
+}
+
+use self::sys::*;
+
+const USER_SIZE: usize = 32;
+
+pub struct Sessions {
+    fd: RawFd,
+}
+
+impl Drop for Sessions {
+    fn drop(&mut self) {
+        let _ = unistd::close(self.fd);
+    }
+}
+
+impl Sessions {
+    /// Must be called before sandboxing
+    pub fn open() -> Option<Sessions> {
+        match fcntl::open(DB_PATH, OFlag::O_RDONLY | OFlag::O_CLOEXEC, stat::Mode::empty()) {
+            Ok(fd) => Some(Sessions { fd }),
+            Err(e) => {
+                warn!("could not open {}: {}", DB_PATH, e);
+                None
+            },
+        }
+    }
+
+    /// Number of login sessions of users other than the given one, None if the database can't be read
+    pub fn others(&self, user: &str) -> Option<usize> {
+        let mut data = Vec::new();
+        let mut chunk = [0u8; 4096];
+        loop {
+            match uio::pread(self.fd, &mut chunk, data.len() as _) {
+                Ok(0) => break,
+                Ok(n) => data.extend_from_slice(&chunk[..n]),
+                Err(e) => {
+                    warn!("reading {}: {}", DB_PATH, e);
+                    return None;
+                },
+            }
+        }
+        let count = data.chunks(RECORD_SIZE)
+            .filter(|rec| rec.len() == RECORD_SIZE && record_type(rec) == USER_PROCESS)
+            .map(|rec| {
+                let name = &rec[USER_OFFSET..USER_OFFSET + USER_SIZE];
+                let end = name.iter().position(|b| *b == 0).unwrap_or(USER_SIZE);
+                &name[..end]
+            })
+            .filter(|name| *name != user.as_bytes())
+            .count();
+        debug!("{} sessions of other users", count);
+        Some(count)
+    }
+}
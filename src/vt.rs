@@ -1,6 +1,13 @@
+// Also used by the lib, for loginw-reset-console
+#![allow(dead_code)]
+
+use std::{panic, thread};
 use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::time::{Duration, Instant};
 use libc;
 use nix::{self, unistd};
+use nix::errno::Errno;
 use nix::sys::{stat, termios};
 use nix::sys::signal::Signal;
 use nix::fcntl::{self, OFlag};
@@ -19,128 +26,461 @@ pub struct VtMode {
     frsig: libc::c_short,
 }
 
-const VT_IOC_MAGIC: char = 'v';
-const VT_AUTO: libc::c_char = 0;
-const VT_PROCESS: libc::c_char = 1;
-const VT_TRUE: libc::c_int = 1;
-const VT_ACKACQ: libc::c_int = 2;
-ioctl_read!(vt_openqry, VT_IOC_MAGIC, 1, libc::c_int);
-ioctl_write_buf!(vt_setmode, VT_IOC_MAGIC, 2, VtMode);
-ioctl_write_int!(vt_reldisp, VT_IOC_MAGIC, 4);
-ioctl_write_int!(vt_activate, VT_IOC_MAGIC, 5);
-ioctl_write_int!(vt_waitactive, VT_IOC_MAGIC, 6);
-ioctl_read!(vt_getmode, VT_IOC_MAGIC, 3, VtMode);
-ioctl_read!(vt_getactive, VT_IOC_MAGIC, 7, libc::c_int);
-ioctl_read!(vt_getindex, VT_IOC_MAGIC, 8, libc::c_int);
-
-const KD_IOC_MAGIC: char = 'K';
-const K_RAW: libc::c_int = 0;
-const KD_TEXT: libc::c_int = 0;
-const KD_GRAPHICS: libc::c_int = 1;
-ioctl_read!(kdgkbmode, KD_IOC_MAGIC, 6, libc::c_int);
-ioctl_write_int!(kdskbmode, KD_IOC_MAGIC, 7);
-ioctl_read!(kdgetmode, KD_IOC_MAGIC, 9, libc::c_int);
-ioctl_write_int!(kdsetmode, KD_IOC_MAGIC, 10);
+#[cfg(target_os = "freebsd")]
+mod sys {
+    use libc;
+    use nix;
+    use super::VtMode;
+
+    pub use nix::sys::termios::cfmakesane;
+
+    pub const TTY_CONTROL: &str = "ttyv0";
+    pub const MAX_VT: libc::c_int = 16;
+
+    pub fn tty_name(vt_num: libc::c_int) -> String {
+        format!("ttyv{}", vt_num - 1)
+    }
+
+    const VT_IOC_MAGIC: char = 'v';
+    pub const VT_AUTO: libc::c_char = 0;
+    pub const VT_PROCESS: libc::c_char = 1;
+    pub const VT_TRUE: libc::c_int = 1;
+    pub const VT_ACKACQ: libc::c_int = 2;
+    ioctl_read!(vt_openqry, VT_IOC_MAGIC, 1, libc::c_int);
+    ioctl_write_buf!(vt_setmode, VT_IOC_MAGIC, 2, VtMode);
+    ioctl_write_int!(vt_reldisp, VT_IOC_MAGIC, 4);
+    ioctl_write_int!(vt_activate, VT_IOC_MAGIC, 5);
+    ioctl_write_int!(vt_waitactive, VT_IOC_MAGIC, 6);
+    ioctl_read!(vt_getmode, VT_IOC_MAGIC, 3, VtMode);
+    ioctl_read!(vt_getactive, VT_IOC_MAGIC, 7, libc::c_int);
+    ioctl_read!(vt_getindex, VT_IOC_MAGIC, 8, libc::c_int);
+
+    const KD_IOC_MAGIC: char = 'K';
+    /// Keyboard mode that stops the console from interpreting keys
+    pub const KB_MUTED: libc::c_int = 0; // K_RAW
+    pub const KB_DEFAULT: libc::c_int = 1; // K_XLATE
+    pub const KD_TEXT: libc::c_int = 0;
+    pub const KD_GRAPHICS: libc::c_int = 1;
+    ioctl_read!(kdgkbmode, KD_IOC_MAGIC, 6, libc::c_int);
+    ioctl_write_int!(kdskbmode, KD_IOC_MAGIC, 7);
+    ioctl_read!(kdgetmode, KD_IOC_MAGIC, 9, libc::c_int);
+    ioctl_write_int!(kdsetmode, KD_IOC_MAGIC, 10);
+
+    /// Not queryable on FreeBSD, so every VT but the one VT_OPENQRY reports as free counts as open
+    pub unsafe fn vt_is_open(tty0: libc::c_int, vt_num: libc::c_int) -> nix::Result<bool> {
+        let mut free = 0;
+        vt_openqry(tty0, &mut free)?;
+        Ok(free != vt_num)
+    }
+
+    /// The VT number of an fd for /dev/tty
+    pub unsafe fn ctty_index(fd: libc::c_int, data: *mut libc::c_int) -> nix::Result<libc::c_int> {
+        vt_getindex(fd, data)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use libc;
+    use nix;
+    use nix::sys::{stat, termios};
+    use nix::sys::termios::{ControlFlags, InputFlags, LocalFlags, OutputFlags};
+    use super::VtMode;
+
+    pub const TTY_CONTROL: &str = "tty0";
+    pub const MAX_VT: libc::c_int = 63;
+
+    pub fn tty_name(vt_num: libc::c_int) -> String {
+        format!("tty{}", vt_num)
+    }
+
+    #[repr(C)]
+    pub struct VtStat {
+        v_active: libc::c_ushort,
+        v_signal: libc::c_ushort,
+        v_state: libc::c_ushort,
+    }
+
+    pub const VT_AUTO: libc::c_char = 0;
+    pub const VT_PROCESS: libc::c_char = 1;
+    pub const VT_TRUE: libc::c_int = 1;
+    pub const VT_ACKACQ: libc::c_int = 2;
+    ioctl_read_bad!(vt_openqry, 0x5600, libc::c_int);
+    ioctl_read_bad!(vt_getmode, 0x5601, VtMode);
+    ioctl_write_ptr_bad!(vt_setmode_ptr, 0x5602, VtMode);
+    ioctl_read_bad!(vt_getstate, 0x5603, VtStat);
+    ioctl_write_int_bad!(vt_reldisp, 0x5605);
+    ioctl_write_int_bad!(vt_activate, 0x5606);
+    ioctl_write_int_bad!(vt_waitactive, 0x5607);
+
+    /// Keyboard mode that stops the console from interpreting keys
+    pub const KB_MUTED: libc::c_int = 4; // K_OFF
+    pub const KB_DEFAULT: libc::c_int = 3; // K_UNICODE
+    pub const KD_TEXT: libc::c_int = 0;
+    pub const KD_GRAPHICS: libc::c_int = 1;
+    ioctl_write_int_bad!(kdsetmode, 0x4B3A);
+    ioctl_read_bad!(kdgetmode, 0x4B3B, libc::c_int);
+    ioctl_read_bad!(kdgkbmode, 0x4B44, libc::c_int);
+    ioctl_write_int_bad!(kdskbmode, 0x4B45);
+    ioctl_read!(tiocgdev, 'T', 0x32, libc::c_uint);
+
+    const TTY_MAJOR: libc::c_uint = 4;
+
+    // Same signatures as the FreeBSD ioctls, to keep the Vt code shared
+
+    pub unsafe fn vt_setmode(fd: libc::c_int, data: &[VtMode]) -> nix::Result<libc::c_int> {
+        vt_setmode_ptr(fd, &data[0])
+    }
+
+    pub unsafe fn vt_getactive(fd: libc::c_int, data: *mut libc::c_int) -> nix::Result<libc::c_int> {
+        let mut state = VtStat { v_active: 0, v_signal: 0, v_state: 0 };
+        let res = vt_getstate(fd, &mut state)?;
+        *data = state.v_active as libc::c_int;
+        Ok(res)
+    }
+
+    /// nix only has it on FreeBSD. Sets the flags of `stty sane`, keeping the line speed.
+    pub fn cfmakesane(tios: &mut termios::Termios) {
+        tios.input_flags = InputFlags::BRKINT | InputFlags::ICRNL | InputFlags::IXON | InputFlags::IMAXBEL | InputFlags::IUTF8;
+        tios.output_flags = OutputFlags::OPOST | OutputFlags::ONLCR;
+        tios.control_flags = (tios.control_flags & !ControlFlags::CSIZE) | ControlFlags::CS8 | ControlFlags::CREAD;
+        tios.local_flags = LocalFlags::ISIG
+            | LocalFlags::ICANON
+            | LocalFlags::ECHO
+            | LocalFlags::ECHOE
+            | LocalFlags::ECHOK
+            | LocalFlags::ECHOCTL
+            | LocalFlags::ECHOKE
+            | LocalFlags::IEXTEN;
+    }
+
+    /// Only known for VTs 1-15, of the others only the one VT_OPENQRY reports as free counts as closed
+    pub unsafe fn vt_is_open(tty0: libc::c_int, vt_num: libc::c_int) -> nix::Result<bool> {
+        if vt_num > 15 {
+            let mut free = 0;
+            vt_openqry(tty0, &mut free)?;
+            return Ok(free != vt_num);
+        }
+        let mut state = VtStat { v_active: 0, v_signal: 0, v_state: 0 };
+        vt_getstate(tty0, &mut state)?;
+        Ok(state.v_state & (1 << vt_num) != 0)
+    }
+
+    /// The VT number of an fd for /dev/tty, where fstat would describe /dev/tty itself
+    pub unsafe fn ctty_index(fd: libc::c_int, data: *mut libc::c_int) -> nix::Result<libc::c_int> {
+        let mut dev: libc::c_uint = 0;
+        tiocgdev(fd, &mut dev)?;
+        // the kernel's new_encode_dev format
+        let (major, minor) = ((dev >> 8) & 0xfff, (dev & 0xff) | ((dev >> 12) & 0xfff00));
+        if major != TTY_MAJOR || minor < 1 || minor > MAX_VT as libc::c_uint {
+            return Err(nix::Error::Sys(nix::errno::Errno::ENOTTY));
+        }
+        *data = minor as libc::c_int;
+        Ok(0)
+    }
+
+    /// There's no VT_GETINDEX on Linux, but the tty minor number is the vt number
+    pub unsafe fn vt_getindex(fd: libc::c_int, data: *mut libc::c_int) -> nix::Result<libc::c_int> {
+        let st = stat::fstat(fd)?;
+        *data = stat::minor(st.st_rdev) as libc::c_int;
+        Ok(0)
+    }
+}
+
+use self::sys::*;
+
+/// The console's usual keyboard mode, for restoring when the original one is unknown
+pub const DEFAULT_KB_MODE: libc::c_int = KB_DEFAULT;
+
+/// How long to wait for a VT switch to complete, the VT being left may never release it
+const SWITCH_TIMEOUT: Duration = Duration::from_secs(5);
+const SWITCH_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Which VT to leave the console on when the Vt is dropped
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OnExit {
+    /// The one that was active when the Vt was set up
+    Original,
+    /// Ours
+    Stay,
+    Switch(libc::c_int),
+}
 
 pub struct Vt {
     pub tty_fd: RawFd,
     pub vt_num: libc::c_int,
     original_kb_mode: libc::c_int,
-    original_vt_num: libc::c_int,
+    /// 0 to stay
+    exit_vt_num: libc::c_int,
+}
+
+/// The VT that the panic hook restores, -1 when there's none (or it was already restored)
+static RESTORE_TTY_FD: AtomicIsize = AtomicIsize::new(-1);
+static RESTORE_KB_MODE: AtomicIsize = AtomicIsize::new(-1);
+static RESTORE_OWN_VT: AtomicIsize = AtomicIsize::new(0);
+static RESTORE_EXIT_VT: AtomicIsize = AtomicIsize::new(0);
+
+/// Restores the console on panic, before unwinding (which may never reach Drop for Vt,
+/// e.g. on a panic inside another Drop, or with panic=abort)
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let tty_fd = RESTORE_TTY_FD.swap(-1, Ordering::SeqCst);
+        if tty_fd >= 0 {
+            let tty_fd = tty_fd as RawFd;
+            let switch = exit_switch(
+                tty_fd,
+                RESTORE_OWN_VT.load(Ordering::SeqCst) as libc::c_int,
+                RESTORE_EXIT_VT.load(Ordering::SeqCst) as libc::c_int,
+            );
+            restore(tty_fd, RESTORE_KB_MODE.load(Ordering::SeqCst) as libc::c_int, switch);
+        }
+        default_hook(info);
+    }));
+}
+
+/// The VT to switch to on exit, None to stay. Also stays when our VT is not on screen anymore,
+/// since the user has switched somewhere else already.
+fn exit_switch(tty_fd: RawFd, own_vt: libc::c_int, exit_vt: libc::c_int) -> Option<libc::c_int> {
+    if exit_vt < 1 || exit_vt == own_vt {
+        return None;
+    }
+    let mut active = 0;
+    match unsafe { vt_getactive(tty_fd, &mut active) } {
+        Ok(_) if active != own_vt => {
+            debug!("vt {} is active instead of vt {}, staying there", active, own_vt);
+            None
+        },
+        _ => Some(exit_vt),
+    }
+}
+
+/// Keyboard mode, text mode, sane termios, VT_AUTO and a switch to the given VT (if any), each step best-effort.
+/// Returns whether every step worked.
+pub fn restore(tty_fd: RawFd, kb_mode: libc::c_int, switch: Option<libc::c_int>) -> bool {
+    let mut ok = true;
+    debug!("setting kbd original mode {}", kb_mode);
+    if let Err(e) = unsafe { kdskbmode(tty_fd, kb_mode) } {
+        warn!("kdskbmode: {}", e);
+        ok = false;
+    }
+    debug!("setting text mode");
+    if let Err(e) = unsafe { kdsetmode(tty_fd, KD_TEXT) } {
+        warn!("kdsetmode: {}", e);
+        ok = false;
+    }
+    debug!("setting termios sane mode");
+    match termios::tcgetattr(tty_fd) {
+        Ok(mut tios) => {
+            cfmakesane(&mut tios);
+            if let Err(e) = termios::tcsetattr(tty_fd, termios::SetArg::TCSAFLUSH, &tios) {
+                warn!("tcsetattr: {}", e);
+                ok = false;
+            }
+        },
+        Err(e) => {
+            warn!("tcgetattr: {}", e);
+            ok = false;
+        },
+    }
+    let mode = VtMode { mode: VT_AUTO, waitv: 0, relsig: 0, acqsig: 0, frsig: 0 };
+    debug!("setting vt mode");
+    if let Err(e) = unsafe { vt_setmode(tty_fd, &[mode]) } {
+        warn!("vt_setmode: {}", e);
+        ok = false;
+    }
+    if let Some(vt_num) = switch {
+        if let Err(e) = switch_to(tty_fd, vt_num) {
+            warn!("switching to vt {}: {}", vt_num, e);
+            ok = false;
+        }
+    }
+    ok
 }
 
 impl Drop for Vt {
     fn drop(&mut self) {
-        debug!("setting kbd original mode {}", self.original_kb_mode);
-        unsafe { kdskbmode(self.tty_fd, self.original_kb_mode) }.expect("kdskbmode");
-        debug!("setting text mode");
-        unsafe { kdsetmode(self.tty_fd, KD_TEXT) }.expect("kdsetmode");
-        debug!("setting termios sane mode");
-        let mut tios = termios::tcgetattr(self.tty_fd).expect("tcgetattr");
-        termios::cfmakesane(&mut tios);
-        termios::tcsetattr(self.tty_fd, termios::SetArg::TCSAFLUSH, &tios).expect("tcsetattr");
-        let mode = VtMode { mode: VT_AUTO, waitv: 0, relsig: 0, acqsig: 0, frsig: 0 };
-        debug!("setting vt mode");
-        unsafe { vt_setmode(self.tty_fd, &[mode]) }.expect("vt_setmode");
-        switch_to(self.tty_fd, self.original_vt_num);
+        // unless the panic hook already did it
+        if RESTORE_TTY_FD.compare_exchange(self.tty_fd as isize, -1, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            let switch = exit_switch(self.tty_fd, self.vt_num, self.exit_vt_num);
+            restore(self.tty_fd, self.original_kb_mode, switch);
+        }
         let _ = unistd::close(self.tty_fd);
     }
 }
 
 impl Vt {
-    pub fn new(tty_fd: RawFd) -> Vt {
+    /// Takes ownership of tty_fd, even on error
+    pub fn new(tty_fd: RawFd, on_exit: OnExit) -> nix::Result<Vt> {
         // vt number is tty number + 1, but get it the proper way anyway
         let mut vt_num = 0;
-        unsafe { vt_getindex(tty_fd, &mut vt_num) }.expect("vt_getindex");
+        let mut original_kb_mode = -1;
+        let mut original_vt_num = 0;
+        let queried = unsafe { vt_getindex(tty_fd, &mut vt_num) }
+            .and_then(|_| unsafe { kdgkbmode(tty_fd, &mut original_kb_mode) })
+            .and_then(|_| unsafe { vt_getactive(tty_fd, &mut original_vt_num) });
+        if let Err(e) = queried {
+            let _ = unistd::close(tty_fd);
+            return Err(e);
+        }
         info!("VT index: {}", vt_num);
+        debug!("VT original kb mode: {}", original_kb_mode);
+        debug!("old active vt number: {}", original_vt_num);
+        let exit_vt_num = match on_exit {
+            OnExit::Original => original_vt_num,
+            OnExit::Stay => 0,
+            OnExit::Switch(n) => n,
+        };
+        // From here on, failures are undone by Drop (or the panic hook)
+        let vt = Vt { tty_fd, vt_num, original_kb_mode, exit_vt_num };
+        RESTORE_KB_MODE.store(original_kb_mode as isize, Ordering::SeqCst);
+        RESTORE_OWN_VT.store(vt_num as isize, Ordering::SeqCst);
+        RESTORE_EXIT_VT.store(exit_vt_num as isize, Ordering::SeqCst);
+        RESTORE_TTY_FD.store(tty_fd as isize, Ordering::SeqCst);
 
         // Set raw mode to mute the console, otherwise everything typed in the compositor
         // could also end up displayed there, including passwords :)
-        let mut original_kb_mode = -1;
-        unsafe { kdgkbmode(tty_fd, &mut original_kb_mode) }.expect("kdgkbmode");
-        debug!("VT original kb mode: {}", original_kb_mode);
         debug!("setting kbd raw mode");
-        unsafe { kdskbmode(tty_fd, K_RAW) }.expect("kdskbmode");
+        unsafe { kdskbmode(tty_fd, KB_MUTED) }?;
         debug!("setting termios raw mode");
-        let mut tios = termios::tcgetattr(tty_fd).expect("tcgetattr");
+        let mut tios = termios::tcgetattr(tty_fd)?;
         termios::cfmakeraw(&mut tios);
-        termios::tcsetattr(tty_fd, termios::SetArg::TCSAFLUSH, &tios).expect("tcsetattr");
+        termios::tcsetattr(tty_fd, termios::SetArg::TCSAFLUSH, &tios)?;
 
         // Set graphics mode and take control!
         debug!("setting graphics mode");
-        unsafe { kdsetmode(tty_fd, KD_GRAPHICS) }.expect("kdsetmode");
+        unsafe { kdsetmode(tty_fd, KD_GRAPHICS) }?;
         let mode = VtMode {
             mode: VT_PROCESS,
             waitv: 0,
             relsig: Signal::SIGUSR1 as libc::c_short,
-            acqsig: Signal::SIGUSR1 as libc::c_short,
+            acqsig: Signal::SIGUSR2 as libc::c_short,
             frsig: Signal::SIGIO as libc::c_short,
         };
         debug!("setting vt mode");
-        unsafe { vt_setmode(tty_fd, &[mode]) }.expect("vt_setmode");
+        unsafe { vt_setmode(tty_fd, &[mode]) }?;
 
-        let mut original_vt_num = 0;
-        unsafe { vt_getactive(tty_fd, &mut original_vt_num) }.expect("vt_getactive");
-        debug!("old active vt number: {}", original_vt_num);
-        switch_to(tty_fd, vt_num);
+        switch_to(tty_fd, vt_num)?;
+
+        Ok(vt)
+    }
 
-        Vt { tty_fd, vt_num, original_kb_mode, original_vt_num }
+    /// Requests a switch to another VT. Does not wait for it, since our own VT
+    /// being released has to go through the release signal handling first.
+    pub fn activate(&self, vt_num: u64) -> nix::Result<()> {
+        if vt_num < 1 || vt_num > MAX_VT as u64 {
+            return Err(nix::Error::invalid_argument());
+        }
+        debug!("activating vt {}", vt_num);
+        unsafe { vt_activate(self.tty_fd, vt_num as libc::c_int) }?;
+        Ok(())
     }
 
     pub fn ack_release(&self) {
         debug!("acknowledging vt release");
-        unsafe { vt_reldisp(self.tty_fd, VT_TRUE) }.expect("vt_reldisp");
+        if let Err(e) = unsafe { vt_reldisp(self.tty_fd, VT_TRUE) } {
+            warn!("vt_reldisp: {}", e);
+        }
     }
 
     pub fn ack_acquire(&self) {
         debug!("acknowledging vt acquire");
-        unsafe { vt_reldisp(self.tty_fd, VT_ACKACQ) }.expect("vt_reldisp");
+        if let Err(e) = unsafe { vt_reldisp(self.tty_fd, VT_ACKACQ) } {
+            warn!("vt_reldisp: {}", e);
+        }
     }
 }
 
-fn switch_to(tty_fd: RawFd, vt_num: libc::c_int) {
+/// Polls instead of VT_WAITACTIVE, which blocks forever if the switch never happens
+fn switch_to(tty_fd: RawFd, vt_num: libc::c_int) -> nix::Result<()> {
     debug!("activating vt {}", vt_num);
-    unsafe { vt_activate(tty_fd, vt_num) }.expect("vt_activate");
+    unsafe { vt_activate(tty_fd, vt_num) }?;
     debug!("waiting for vt {} activation", vt_num);
-    unsafe { vt_waitactive(tty_fd, vt_num) }.expect("vt_waitactive");
+    let deadline = Instant::now() + SWITCH_TIMEOUT;
+    loop {
+        let mut active = 0;
+        unsafe { vt_getactive(tty_fd, &mut active) }?;
+        if active == vt_num {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(nix::Error::Sys(Errno::ETIMEDOUT));
+        }
+        thread::sleep(SWITCH_POLL_INTERVAL);
+    }
 }
 
-pub fn open_tty(dev_dir: RawFd, tty_num: libc::c_int) -> nix::Result<RawFd> {
-    debug!("opening ttyv{}", tty_num);
+pub fn open_vt(dev_dir: RawFd, vt_num: libc::c_int) -> nix::Result<RawFd> {
+    let name = tty_name(vt_num);
+    debug!("opening {}", name);
     fcntl::openat(
         dev_dir,
-        &format!("ttyv{}", tty_num) as &str,
+        &name as &str,
         OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC,
         stat::Mode::empty(),
     )
 }
 
-pub fn find_free_tty(dev_dir: RawFd) -> nix::Result<libc::c_int> {
-    debug!("finding free tty");
-    let tty0 = fcntl::openat(dev_dir, "ttyv0", OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC, stat::Mode::empty())?;
+/// The number of the VT currently on screen
+pub fn active_vt(dev_dir: RawFd) -> nix::Result<libc::c_int> {
+    let tty0 = fcntl::openat(dev_dir, TTY_CONTROL, OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC, stat::Mode::empty())?;
+    let mut vt_num = 0;
+    let res = unsafe { vt_getactive(tty0, &mut vt_num) };
+    let _ = unistd::close(tty0);
+    res?;
+    Ok(vt_num)
+}
+
+/// The VT that loginw was started from, if any. Must be called before sandboxing.
+pub fn controlling_vt() -> Option<libc::c_int> {
+    let fd = fcntl::open("/dev/tty", OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC, stat::Mode::empty()).ok()?;
+    let mut vt_num = 0;
+    let res = unsafe { ctty_index(fd, &mut vt_num) };
+    let _ = unistd::close(fd);
+    res.ok()?;
+    debug!("controlling vt {}", vt_num);
+    Some(vt_num)
+}
+
+/// Fails with EBUSY if the VT is used by something else: open (or not known to be closed),
+/// controlled by another process (VT_PROCESS) or in graphics mode.
+/// own_vt (from controlling_vt) is always available.
+pub fn check_available(dev_dir: RawFd, vt_num: libc::c_int, own_vt: Option<libc::c_int>) -> nix::Result<()> {
+    if !(1..=MAX_VT).contains(&vt_num) {
+        return Err(nix::Error::invalid_argument());
+    }
+    if own_vt == Some(vt_num) {
+        return Ok(());
+    }
+    let busy = || nix::Error::Sys(Errno::EBUSY);
+    let tty0 = fcntl::openat(dev_dir, TTY_CONTROL, OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC, stat::Mode::empty())?;
+    // before opening it ourselves, which would count
+    let open = unsafe { vt_is_open(tty0, vt_num) };
+    let _ = unistd::close(tty0);
+    if open? {
+        debug!("vt {} is open", vt_num);
+        return Err(busy());
+    }
+    let tty_fd = open_vt(dev_dir, vt_num)?;
+    let mut mode = VtMode { mode: VT_AUTO, waitv: 0, relsig: 0, acqsig: 0, frsig: 0 };
+    let mut kd_mode = KD_TEXT;
+    let res = unsafe { vt_getmode(tty_fd, &mut mode) }.and_then(|_| unsafe { kdgetmode(tty_fd, &mut kd_mode) });
+    let _ = unistd::close(tty_fd);
+    res?;
+    if mode.mode != VT_AUTO || kd_mode != KD_TEXT {
+        debug!("vt {} is in use (vt mode {}, kd mode {})", vt_num, mode.mode, kd_mode);
+        return Err(busy());
+    }
+    Ok(())
+}
+
+pub fn find_free_vt(dev_dir: RawFd) -> nix::Result<libc::c_int> {
+    debug!("finding free vt");
+    let tty0 = fcntl::openat(dev_dir, TTY_CONTROL, OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC, stat::Mode::empty())?;
     let mut vt_num = 0;
-    unsafe { vt_openqry(tty0, &mut vt_num) }?;
-    debug!("found free vt {} (tty {})", vt_num, vt_num - 1);
-    unistd::close(tty0)?;
-    Ok(vt_num - 1)
+    let res = unsafe { vt_openqry(tty0, &mut vt_num) };
+    let _ = unistd::close(tty0);
+    res?;
+    debug!("found free vt {} ({})", vt_num, tty_name(vt_num));
+    Ok(vt_num)
 }